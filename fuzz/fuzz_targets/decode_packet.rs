@@ -0,0 +1,19 @@
+// Feeds arbitrary bytes through what the server does with every packet it
+// gets, and what clients do with packets from the server. Nothing may panic.
+//
+//     cargo +nightly fuzz run decode_packet
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use pong_deathmatch::codec::decode_server_msg;
+use pong_deathmatch::packet_filter::PacketFilter;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+fuzz_target!(|data: &[u8]| {
+    let addr: SocketAddr = "127.0.0.1:6666".parse().unwrap();
+    let mut filter = PacketFilter::new();
+    let _ = filter.check(addr, data, Instant::now());
+
+    let _ = decode_server_msg(data);
+});
@@ -26,17 +26,17 @@ echo "================================"
 echo "Running a game for a few seconds"
 echo "================================"
 
-cargo run -- -s &
+cargo run --bin pong-server &
 SERVER_PID="$!"
 
 sleep 2
 
-cargo run -- --cpu &
+cargo run --bin pong-client -- --cpu &
 P1_PID="$!"
 
 sleep 2
 
-cargo run -- --cpu &
+cargo run --bin pong-client -- --cpu &
 P2_PID="$!"
 
 sleep 10
@@ -11,17 +11,17 @@ cleanup() {
 
 trap 'cleanup' SIGINT
 
-cargo run -- -s &
+cargo run --bin pong-server &
 SERVER_PID=$!
 
 sleep 2
 
-cargo run -- --cpu &
+cargo run --bin pong-client -- --cpu &
 P1_PID=$!
 
 sleep 2
 
-cargo run -- --cpu &
+cargo run --bin pong-client -- --cpu &
 P2_PID=$!
 
 sleep 600
@@ -0,0 +1,71 @@
+// When to try again after losing contact with the server.
+//
+// The first attempt is made right away. After that the wait doubles for every
+// attempt, up to a limit, so that a server that is down isn't flooded while one
+// that just hiccuped is found again quickly.
+
+use crate::game_constants::{RECONNECT_MIN_BACKOFF_MS, RECONNECT_MAX_BACKOFF_MS};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub struct Backoff {
+    // How long to wait after the next attempt.
+    delay: Duration,
+    next_attempt: Instant,
+}
+
+impl Backoff {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            delay: Duration::from_millis(RECONNECT_MIN_BACKOFF_MS),
+            next_attempt: now,
+        }
+    }
+
+    // True when it's time for another attempt, which is then counted as made.
+    pub fn attempt_due(&mut self, now: Instant) -> bool {
+        if now < self.next_attempt {
+            return false;
+        }
+
+        self.next_attempt = now + self.delay;
+        self.delay = (self.delay * 2).min(Duration::from_millis(RECONNECT_MAX_BACKOFF_MS));
+        true
+    }
+}
+
+#[cfg(test)]
+mod backoff_tests {
+    use super::*;
+
+    #[test]
+    fn test_first_attempt_is_right_away() {
+        let now = Instant::now();
+        let mut backoff = Backoff::new(now);
+
+        assert!(backoff.attempt_due(now));
+        assert!(!backoff.attempt_due(now));
+    }
+
+    #[test]
+    fn test_wait_doubles_up_to_the_limit() {
+        let start = Instant::now();
+        let mut backoff = Backoff::new(start);
+        let mut now = start;
+        let mut waits = vec![];
+
+        backoff.attempt_due(now);
+        for _ in 0..10 {
+            let attempt = now;
+            while !backoff.attempt_due(now) {
+                now += Duration::from_millis(1);
+            }
+            waits.push((now - attempt).as_millis() as u64);
+        }
+
+        assert_eq!(waits[0], RECONNECT_MIN_BACKOFF_MS);
+        assert_eq!(waits[1], RECONNECT_MIN_BACKOFF_MS * 2);
+        assert_eq!(waits[2], RECONNECT_MIN_BACKOFF_MS * 4);
+        assert_eq!(*waits.last().unwrap(), RECONNECT_MAX_BACKOFF_MS);
+    }
+}
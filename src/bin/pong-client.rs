@@ -0,0 +1,51 @@
+use pong_deathmatch::client::run as run_client;
+use pong_deathmatch::client_connection::Profile;
+use pong_deathmatch::game_constants::INTERPOLATION_DELAY_MS;
+use pong_deathmatch::link_conditioner::LinkOpts;
+
+use clap::Clap;
+use std::time::Duration;
+
+/// Pong client, plays against another client via a server.
+#[derive(Clap)]
+struct Opts {
+    /// The server address to send to
+    #[clap(short, long, default_value = "127.0.0.1:6666")]
+    addr: String,
+
+    /// Bad-mannered computer player
+    #[clap(long)]
+    cpu: bool,
+
+    /// The name the opponent and spectators see
+    #[clap(long, default_value = "")]
+    name: String,
+
+    /// Watch a running match instead of playing
+    #[clap(long)]
+    spectate: bool,
+
+    /// How many ms in the past the opponent and the ball are drawn
+    #[clap(long)]
+    interp_delay: Option<u64>,
+
+    #[clap(flatten)]
+    link: LinkOpts,
+}
+
+fn main() {
+    let opts: Opts = Opts::parse();
+
+    let conditions = opts.link.link_conditions();
+    let cpu = opts.cpu;
+    let addr = opts.addr;
+    let interp_delay = opts.interp_delay.unwrap_or(INTERPOLATION_DELAY_MS);
+    let profile = Profile { name: opts.name, spectator: opts.spectate };
+    println!("Server address: {}", &addr);
+
+    if let Err(e) = run_client(&addr, cpu, Duration::from_millis(interp_delay), conditions, profile) {
+        println!("error: {}", e);
+
+        std::process::exit(1);
+    }
+}
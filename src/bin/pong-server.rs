@@ -0,0 +1,35 @@
+use pong_deathmatch::link_conditioner::LinkOpts;
+use pong_deathmatch::matchmaking::Pairing;
+use pong_deathmatch::server::run as run_server;
+
+use clap::Clap;
+
+/// Pong server, pairs up clients and hosts their matches.
+#[derive(Clap)]
+struct Opts {
+    /// The address to receive on
+    #[clap(short, long, default_value = "127.0.0.1:6666")]
+    addr: String,
+
+    /// Pair waiting players with similar ping, rather than first come first served
+    #[clap(long)]
+    pair_by_ping: bool,
+
+    #[clap(flatten)]
+    link: LinkOpts,
+}
+
+fn main() {
+    let opts: Opts = Opts::parse();
+
+    let conditions = opts.link.link_conditions();
+    let pairing = if opts.pair_by_ping { Pairing::ClosestPing } else { Pairing::FirstCome };
+    let addr = opts.addr;
+    println!("Server address: {}", &addr);
+
+    if let Err(e) = run_server(&addr, conditions, pairing) {
+        println!("error: {}", e);
+
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,66 @@
+// Which messages need to arrive and which are useless once a newer one is
+// out. The transport sends every channel as a stream of its own, so a lost
+// state never holds back newer states or anything else.
+
+use crate::net_messages::{ClientMsg, ServerMsg};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Channel {
+    // Handshakes and changes in the match, like starts, aborts and results.
+    // Reliable and ordered.
+    Control,
+
+    // States from the server and the acks for them. Only the newest counts,
+    // older ones arriving late are dropped.
+    State,
+
+    // Movement changes. Only the newest counts, clients resend their latest
+    // change until the server has seen it.
+    Input,
+
+    // Pings and pongs. Only the newest counts.
+    Clock,
+}
+
+impl Channel {
+    pub fn for_server_msg(msg: &ServerMsg) -> Self {
+        match msg {
+            ServerMsg::State(..) | ServerMsg::StateDelta(..) => Channel::State,
+            ServerMsg::Ping(_) => Channel::Clock,
+            _ => Channel::Control,
+        }
+    }
+
+    pub fn for_client_msg(msg: &ClientMsg) -> Self {
+        match msg {
+            ClientMsg::AckState(_) => Channel::State,
+            ClientMsg::Move(_) => Channel::Input,
+            ClientMsg::Pong(_) => Channel::Clock,
+            _ => Channel::Control,
+        }
+    }
+}
+
+#[cfg(test)]
+mod channels_tests {
+    use crate::net_messages::{InputAck, MoveInput, PlayerOrder, SessionToken};
+    use crate::pong_state::{PongState, PlayerMovement};
+    use super::*;
+
+    #[test]
+    fn test_states_and_control_use_different_channels() {
+        let state = ServerMsg::State(PongState::new(), InputAck::default());
+        assert_eq!(Channel::for_server_msg(&state), Channel::State);
+
+        let start = ServerMsg::Start(PlayerOrder::P1, SessionToken(1));
+        assert_eq!(Channel::for_server_msg(&start), Channel::Control);
+    }
+
+    #[test]
+    fn test_moves_and_acks_use_different_streams() {
+        let movement = ClientMsg::Move(MoveInput { seq: 1, movement: PlayerMovement::Up, tick: 0 });
+        let ack = ClientMsg::AckState(1);
+
+        assert_ne!(Channel::for_client_msg(&movement), Channel::for_client_msg(&ack));
+    }
+}
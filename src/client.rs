@@ -1,6 +1,11 @@
-use crate::client_connection::ClientConnection;
-use crate::net_messages::{ServerMsg, PlayerOrder};
+use crate::backoff::Backoff;
+use crate::client_connection::{ClientConnection, Profile};
+use crate::link_conditioner::LinkConditions;
+use crate::error::{Error, Result};
+use crate::net_messages::{ServerMsg, PlayerOrder, MatchResult, InputAck};
 use crate::pong_state::{PongState, PlayerMovement};
+use crate::prediction::Prediction;
+use crate::interpolation::SnapshotBuffer;
 use crate::game_constants::{
     PADDLE_WIDTH,
     PADDLE_HEIGHT,
@@ -13,6 +18,7 @@ use crate::game_constants::{
 };
 use crate::game_constants::{
     MS_PER_UPDATE,
+    SPECTATE_RETRY_MS,
 };
 
 use ggez::event::{self, EventHandler, KeyCode, KeyMods};
@@ -20,17 +26,24 @@ use ggez::{graphics, Context, ContextBuilder, GameResult};
 
 use std::time::{Duration, Instant};
 
-pub fn run(addr: &str, cpu: bool) {
+pub fn run(
+    addr: &str,
+    cpu: bool,
+    interpolation_delay: Duration,
+    conditions: LinkConditions,
+    profile: Profile
+) -> Result<()> {
     let (mut ctx, mut event_loop) = ContextBuilder::new("PONG", "Karl Johansson")
         .window_mode(ggez::conf::WindowMode::default().dimensions(
             GAME_WIDTH as f32, GAME_HEIGHT as f32)
         )
         .build()
-        .expect("Could not create ggez context!");
+        .map_err(|err| Error::Graphics(err.to_string()))?;
 
-    let connection = ClientConnection::connect(&addr).expect("Can't send any packets");
-    let mut client_game = ClientGame::new(&mut ctx, connection, cpu);
-    event::run(&mut ctx, &mut event_loop, &mut client_game).expect("Game crashed");
+    let connection = ClientConnection::connect(addr, conditions, profile)?;
+    let mut client_game = ClientGame::new(&mut ctx, connection, cpu, interpolation_delay);
+    event::run(&mut ctx, &mut event_loop, &mut client_game)
+        .map_err(|err| Error::Graphics(err.to_string()))
 }
 
 struct ClientGame {
@@ -44,10 +57,47 @@ struct ClientGame {
     // Am I player 1 or 2, could be fixed with some nice polymorophism instead.
     player: Option<PlayerOrder>,
 
+    // The names of player 1 and 2 while we're watching their match.
+    watching: Option<(String, String)>,
+
+    // When to ask for a match to watch again, after there was none.
+    next_spectate: Option<Instant>,
+
+    // Our own not yet acknowledged movement, replayed on top of every state
+    // from the server.
+    prediction: Option<Prediction>,
+
+    // Recent states from the server, used to draw the opponent and the ball
+    // smoothly.
+    snapshots: SnapshotBuffer,
+
+    // The outcome of the last finished match, shown until a new one starts.
+    last_result: Option<MatchResult>,
+
+    // Our place in the queue while waiting for an opponent.
+    queue_position: Option<u32>,
+
+    // While the opponent has lost contact, how many seconds they have left to
+    // come back before the match is aborted.
+    opponent_away: Option<u32>,
+
+    // Set while we have lost contact with the server and are trying to get
+    // back in.
+    reconnecting: Option<Backoff>,
+
+    // The latest problem worth telling the player about, shown instead of the
+    // game.
+    error: Option<Error>,
+
     // Keeps track of key presses
     up: bool,
     down: bool,
 
+    // The movement we last told the server about, and how many movement
+    // changes we have sent during this match.
+    sent_movement: Option<PlayerMovement>,
+    movement_seq: u32,
+
     // Dictates whether this client is human or machine.
     cpu: bool,
 
@@ -57,14 +107,30 @@ struct ClientGame {
 
 
 impl ClientGame {
-    pub fn new(_ctx: &mut Context, connection: ClientConnection, cpu: bool) -> Self {
+    pub fn new(
+        _ctx: &mut Context,
+        connection: ClientConnection,
+        cpu: bool,
+        interpolation_delay: Duration
+    ) -> Self {
         Self {
             connection,
             cpu,
             player: None,
+            watching: None,
+            next_spectate: None,
+            prediction: None,
+            snapshots: SnapshotBuffer::new(interpolation_delay),
+            last_result: None,
+            queue_position: None,
+            opponent_away: None,
+            reconnecting: None,
+            error: None,
             pong_state: None,
             up: false,
             down: false,
+            sent_movement: None,
+            movement_seq: 0,
             last_update: Instant::now(),
         }
     }
@@ -76,19 +142,23 @@ impl ClientGame {
             return Ok(());
         }
 
+        self.maybe_reconnect();
+        self.maybe_spectate_again();
         self.poll_server_events();
-        self.extrapolate();
 
-        if self.game_has_started() {
-            self.update_cpu_movement();
+        // Without the server the game stands still, like it does there.
+        if self.reconnecting.is_some() {
+            self.last_update = Instant::now();
+            return Ok(());
+        }
 
-            if self.moving_up() {
-                self.connection.send_move_up().expect("Failed to send movement");
-            } else if self.moving_down() {
-                self.connection.send_move_down().expect("Failed to send movement");
-            }
+        if self.game_has_started() && self.player.is_some() {
+            self.update_cpu_movement();
+            self.maybe_send_movement();
         }
 
+        self.extrapolate();
+
         self.last_update = Instant::now();
         Ok(())
     }
@@ -112,6 +182,100 @@ impl ClientGame {
         }
     }
 
+    // Only changes in movement are sent, the server keeps moving our paddle
+    // the same way until it hears otherwise.
+    fn maybe_send_movement(&mut self) {
+        let movement = self.movement();
+        if self.sent_movement == Some(movement) {
+            return;
+        }
+
+        self.movement_seq += 1;
+        if let Err(err) = self.connection.send_movement(self.movement_seq, movement) {
+            self.show_error(err);
+        }
+        self.sent_movement = Some(movement);
+    }
+
+    // Movement changes are sent unreliably. Until a state from the server
+    // shows that it has seen our latest change, it's sent again.
+    fn resend_unacked_movement(&mut self, ack: InputAck) {
+        if let Some(movement) = self.sent_movement {
+            if ack.seq < self.movement_seq {
+                if let Err(err) = self.connection.send_movement(self.movement_seq, movement) {
+                    self.show_error(err);
+                }
+            }
+        }
+    }
+
+    // Problems are shown in the window rather than crashing the game.
+    fn show_error(&mut self, err: Error) {
+        println!("{}", err);
+
+        match err {
+            // A bad packet now and then is nothing to bother the player with.
+            Error::Decode(_) => {}
+
+            // There's no game to go on with.
+            Error::Protocol(_) => {
+                self.abort_game();
+                self.error = Some(err);
+            }
+
+            // The server might still be there, or come back.
+            Error::Timeout(_) => self.lost_contact(),
+
+            _ => self.error = Some(err),
+        }
+    }
+
+    fn lost_contact(&mut self) {
+        if self.reconnecting.is_none() {
+            println!("Lost contact with the server, reconnecting");
+            self.queue_position = None;
+            self.reconnecting = Some(Backoff::new(Instant::now()));
+        }
+    }
+
+    fn maybe_reconnect(&mut self) {
+        let attempt_due = match &mut self.reconnecting {
+            Some(backoff) => backoff.attempt_due(Instant::now()),
+            None => false,
+        };
+
+        if attempt_due {
+            if let Err(err) = self.connection.reconnect() {
+                self.show_error(err);
+            }
+        }
+    }
+
+    fn greet_server(&mut self) {
+        if let Err(err) = self.connection.greet_server() {
+            self.show_error(err);
+        }
+    }
+
+    // Players queue up right away, spectators give the server a while to
+    // start a match for them to watch.
+    fn look_for_match(&mut self) {
+        if self.connection.is_spectator() {
+            self.next_spectate = Some(Instant::now() + Duration::from_millis(SPECTATE_RETRY_MS));
+        } else {
+            self.greet_server();
+        }
+    }
+
+    fn maybe_spectate_again(&mut self) {
+        if let Some(next_spectate) = self.next_spectate {
+            if Instant::now() >= next_spectate {
+                self.next_spectate = None;
+                self.greet_server();
+            }
+        }
+    }
+
     fn game_has_started(&self) -> bool {
         self.pong_state.is_some()
     }
@@ -119,6 +283,10 @@ impl ClientGame {
     fn abort_game(&mut self) {
         self.pong_state = None;
         self.player = None;
+        self.watching = None;
+        self.prediction = None;
+        self.opponent_away = None;
+        self.snapshots.clear();
     }
 
     fn update_cpu_movement(&mut self) {
@@ -160,23 +328,87 @@ impl ClientGame {
 
     fn poll_server_events(&mut self) {
         while let Some(event) = self.connection.receive() {
+            let event = match event {
+                Ok(event) => {
+                    // Whatever the server says, it's there.
+                    if self.reconnecting.take().is_some() {
+                        println!("Reconnected");
+                    }
+                    event
+                }
+                Err(err) => {
+                    self.show_error(err);
+                    continue;
+                }
+            };
+
             match event {
-                ServerMsg::Start(order) => {
+                ServerMsg::Start(order, _) => {
                     // Am I Player 1 or Player 2?
                     self.player = Some(order);
+                    self.prediction = Some(Prediction::new(order));
+                    self.queue_position = None;
+                    self.last_result = None;
+                    self.error = None;
+
+                    // The server counts movement changes per match.
+                    self.sent_movement = None;
+                    self.movement_seq = 0;
+                }
+
+                ServerMsg::Watching(p1_name, p2_name) => {
+                    println!("Watching {} against {}", p1_name, p2_name);
+                    self.watching = Some((p1_name, p2_name));
+                    self.last_result = None;
+                    self.error = None;
                 }
 
-                ServerMsg::State(state) => {
-                    self.pong_state = Some(state);
+                ServerMsg::State(state, ack) => {
+                    // Late states from a match that is over.
+                    if self.player.is_none() && self.watching.is_none() {
+                        continue;
+                    }
+
+                    // States only come while the match is going on.
+                    self.opponent_away = None;
+
+                    self.resend_unacked_movement(ack);
+                    self.snapshots.push(Instant::now(), state);
+                    self.pong_state = match &mut self.prediction {
+                        Some(prediction) => Some(prediction.reconcile(state, ack)),
+                        None => Some(state),
+                    };
+                }
+
+                ServerMsg::MatchOver(result) => {
+                    println!(
+                        "{:?} won the match {} - {}",
+                        result.winner, result.p1_score, result.p2_score
+                    );
+                    self.last_result = Some(result);
+
+                    // Queue up for, or watch, another match.
+                    self.abort_game();
+                    self.look_for_match();
                 }
 
                 ServerMsg::Abort => {
                     self.abort_game();
-                    self.connection.greet_server().expect("Failed to greet server");
+                    self.look_for_match();
                 }
 
-                ServerMsg::Ping => {
-                    self.connection.pong().expect("Failed to ping server");
+                ServerMsg::Queued(position) => {
+                    self.queue_position = Some(position);
+                }
+
+                ServerMsg::Paused(seconds_left) => {
+                    self.opponent_away = Some(seconds_left);
+                }
+
+                ServerMsg::Ping(clock) => {
+                    if let Err(err) = self.connection.pong(clock) {
+                        self.show_error(err);
+                    }
                 }
 
                 _ => {}
@@ -188,19 +420,22 @@ impl ClientGame {
         // Fetch our own movement so we can use it when extrapolating. It will
         // make our own movement smoother.
         let movement = self.movement();
+        let seq = self.movement_seq;
 
-        if let Some(state) = &mut self.pong_state {
-            match self.player {
-                Some(PlayerOrder::P1) => {
-                    state.extrapolate_p1(movement);
-                }
+        if let (Some(state), Some(prediction)) = (&mut self.pong_state, &mut self.prediction) {
+            prediction.predict(state, seq, movement);
+        }
+    }
 
-                Some(PlayerOrder::P2) => {
-                    state.extrapolate_p2(movement);
-                }
+    // Our own paddle is drawn where we predict it to be, while the opponent
+    // and the ball are interpolated between states from the server.
+    fn positions_to_draw(&self, predicted: PongState) -> (i32, i32, (i32, i32)) {
+        let remote = self.snapshots.sample(Instant::now()).unwrap_or(predicted);
 
-                _ => {}
-            }
+        match self.player {
+            Some(PlayerOrder::P1) => (predicted.p1, remote.p2, remote.ball),
+            Some(PlayerOrder::P2) => (remote.p1, predicted.p2, remote.ball),
+            None => (remote.p1, remote.p2, remote.ball),
         }
     }
 }
@@ -215,7 +450,14 @@ impl EventHandler for ClientGame {
     fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
         graphics::clear(ctx, graphics::BLACK);
 
-        if let Some(state) = self.pong_state {
+        if let Some(err) = &self.error {
+            let text = graphics::Text::new(format!("{}", err));
+            graphics::draw(
+                ctx,
+                &text,
+                (ggez::mint::Point2 { x: 20.0, y: (GAME_HEIGHT / 2) as f32 },)
+            )?;
+        } else if let Some(state) = self.pong_state {
             let color = [1.0, 1.0, 1.0, 1.0].into();
             let player_rect =
                 graphics::Mesh::new_rectangle(
@@ -233,9 +475,7 @@ impl EventHandler for ClientGame {
                     color
                 )?;
 
-            let p1_y = state.p1;
-            let p2_y = state.p2;
-            let (ball_x, ball_y) = state.ball;
+            let (p1_y, p2_y, (ball_x, ball_y)) = self.positions_to_draw(state);
 
             graphics::draw(
                 ctx,
@@ -254,6 +494,81 @@ impl EventHandler for ClientGame {
                 &ball_rect,
                 (ggez::mint::Point2 { x: ball_x as f32, y: ball_y as f32 },)
             )?;
+
+            let score = graphics::Text::new(
+                format!("{}   {}", state.p1_score, state.p2_score)
+            );
+            graphics::draw(
+                ctx,
+                &score,
+                (ggez::mint::Point2 { x: (GAME_WIDTH / 2 - 20) as f32, y: 10.0 },)
+            )?;
+
+            // Spectators see who is playing on either side of the score.
+            if let Some((p1_name, p2_name)) = &self.watching {
+                let p1_text = graphics::Text::new(p1_name.as_str());
+                let p1_x = GAME_WIDTH as f32 / 2.0 - 40.0 - p1_text.width(ctx) as f32;
+                graphics::draw(ctx, &p1_text, (ggez::mint::Point2 { x: p1_x, y: 10.0 },))?;
+
+                let p2_text = graphics::Text::new(p2_name.as_str());
+                graphics::draw(
+                    ctx,
+                    &p2_text,
+                    (ggez::mint::Point2 { x: (GAME_WIDTH / 2 + 50) as f32, y: 10.0 },)
+                )?;
+            }
+
+            if let Some(seconds_left) = self.opponent_away {
+                let text = graphics::Text::new(
+                    format!("Waiting for the opponent to come back.. {}", seconds_left)
+                );
+                graphics::draw(
+                    ctx,
+                    &text,
+                    (ggez::mint::Point2 { x: 20.0, y: (GAME_HEIGHT / 2) as f32 },)
+                )?;
+            }
+        } else if let Some(result) = self.last_result {
+            let text = graphics::Text::new(
+                format!(
+                    "{:?} won {} - {}",
+                    result.winner, result.p1_score, result.p2_score
+                )
+            );
+            graphics::draw(
+                ctx,
+                &text,
+                (ggez::mint::Point2 { x: (GAME_WIDTH / 2 - 50) as f32, y: (GAME_HEIGHT / 2) as f32 },)
+            )?;
+        }
+
+        if let (None, None, Some(position)) = (&self.error, self.pong_state, self.queue_position) {
+            let text = graphics::Text::new(
+                format!("Waiting for an opponent, number {} in line", position)
+            );
+            graphics::draw(
+                ctx,
+                &text,
+                (ggez::mint::Point2 { x: 20.0, y: (GAME_HEIGHT / 2 + 30) as f32 },)
+            )?;
+        }
+
+        if let (None, None, Some(_)) = (&self.error, self.pong_state, self.next_spectate) {
+            let text = graphics::Text::new("No match to watch yet, looking again..");
+            graphics::draw(
+                ctx,
+                &text,
+                (ggez::mint::Point2 { x: 20.0, y: (GAME_HEIGHT / 2 + 30) as f32 },)
+            )?;
+        }
+
+        if self.error.is_none() && self.reconnecting.is_some() {
+            let text = graphics::Text::new("Lost contact with the server, reconnecting..");
+            graphics::draw(
+                ctx,
+                &text,
+                (ggez::mint::Point2 { x: 20.0, y: (GAME_HEIGHT / 2 - 30) as f32 },)
+            )?;
         }
 
         graphics::present(ctx)
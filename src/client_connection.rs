@@ -1,90 +1,212 @@
-use bincode::{deserialize, serialize};
-use crate::net_messages::{ClientMsg, ServerMsg};
-use crossbeam_channel::{Sender, Receiver};
-use laminar::{Packet, Socket, SocketEvent};
+use crate::channels::Channel;
+use crate::clock::ServerClock;
+use crate::codec::{encode_client_msg, decode_server_msg, DecodeError};
+use crate::delta::{DeltaDecoder, Snapshot};
+use crate::error::{Error, Result, parse_addr};
+use crate::link_conditioner::{ConditionedTransport, LinkConditions};
+use crate::net_messages::{
+    ClientMsg, ServerMsg, MoveInput, ClockSync, InputAck, Hello, RejectReason, SessionToken,
+};
+use crate::pong_state::PlayerMovement;
+use crate::transport::{Transport, TransportEvent, ThreadedLaminarTransport};
 use std::net::SocketAddr;
-use std::thread;
+use std::time::{Duration, Instant};
 
-#[derive(Debug)]
-pub enum ConnectionError {
-    FailedToSend(String)
+// Who we are to the server.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    // Shown to the opponent and to spectators, empty to stay anonymous.
+    pub name: String,
+
+    // Watch a match instead of queueing up to play.
+    pub spectator: bool,
 }
 
 pub struct ClientConnection {
     server_addr: SocketAddr,
 
-    // channel ends for sending/receiving data to/from a socket
-    sender: Sender<Packet>,
-    receiver: Receiver<SocketEvent>,
+    transport: Box<dyn Transport>,
+
+    // Estimates of latency and the server clock, kept up to date from pings
+    // and states.
+    clock: ServerClock,
+
+    // Rebuilds whole states from the deltas the server sends.
+    decoder: DeltaDecoder,
+
+    // From the start of the match we're in, lets us back in if we lose
+    // contact with the server.
+    session: Option<SessionToken>,
+
+    profile: Profile,
 }
 
 impl ClientConnection {
-    pub fn connect(server_addr: &str) -> Result<Self, ConnectionError> {
-        let server_addr: SocketAddr = server_addr.parse().unwrap();
+    pub fn connect(
+        server_addr: &str,
+        conditions: LinkConditions,
+        profile: Profile
+    ) -> Result<Self> {
+        let server_addr = parse_addr(server_addr)?;
 
-        let socket = Socket::bind_any().unwrap();
-        let sender = socket.get_packet_sender();
-        let receiver = socket.get_event_receiver();
+        let mut transport: Box<dyn Transport> = Box::new(ThreadedLaminarTransport::bind_any()?);
+        if !conditions.is_perfect() {
+            println!("Simulating network conditions: {:?}", conditions);
+            transport = Box::new(ConditionedTransport::new(transport, conditions));
+        }
 
-        // Network communication is run in another thread to not mess up client
-        // updating/drawing rates.
-        Self::poll_in_separate_thread(socket);
+        Self::with_transport(server_addr, transport, profile)
+    }
 
-        let mut connection = Self { server_addr, sender, receiver };
+    pub fn with_transport(
+        server_addr: SocketAddr,
+        transport: Box<dyn Transport>,
+        profile: Profile
+    ) -> Result<Self> {
+        let clock = ServerClock::new();
+        let decoder = DeltaDecoder::new();
+        let session = None;
+        let mut connection = Self { server_addr, transport, clock, decoder, session, profile };
         connection.greet_server()?;
         Ok(connection)
     }
 
-    pub fn poll_in_separate_thread(mut socket: Socket) {
-        thread::spawn(move || socket.start_polling());
+    pub fn is_spectator(&self) -> bool {
+        self.profile.spectator
+    }
+
+    // Queues up for a match, or asks for one to watch.
+    pub fn greet_server(&mut self) -> Result<()> {
+        let hello = self.hello();
+        if self.profile.spectator {
+            self.send(ClientMsg::Spectate(hello))
+        } else {
+            self.send(ClientMsg::Hi(hello))
+        }
+    }
+
+    fn hello(&self) -> Hello {
+        Hello::with_name(&self.profile.name)
     }
 
-    pub fn greet_server(&mut self) -> Result<(), ConnectionError> {
-        self.send(ClientMsg::Hi)
+    // Asks to get back into the match we were in. False if we weren't in one,
+    // then there's nothing to resume and we have to say hi instead.
+    pub fn resume(&mut self) -> Result<bool> {
+        match self.session {
+            Some(token) => self.send(ClientMsg::Resume(self.hello(), token)).map(|_| true),
+            None => Ok(false),
+        }
     }
 
-    pub fn send_move_up(&mut self) -> Result<(), ConnectionError> {
-        self.send(ClientMsg::MoveUp)
+    // Tries to get back in touch with the server after losing contact, into
+    // the match we were in if there was one, otherwise into the queue.
+    pub fn reconnect(&mut self) -> Result<()> {
+        if !self.resume()? {
+            self.greet_server()?;
+        }
+        Ok(())
     }
 
-    pub fn send_move_down(&mut self) -> Result<(), ConnectionError> {
-        self.send(ClientMsg::MoveDown)
+    // Tells the server that our movement changed, from the tick it will be on
+    // once it hears about it. That's when our prediction has moved the paddle.
+    pub fn send_movement(&mut self, seq: u32, movement: PlayerMovement) -> Result<()> {
+        let arrival = Instant::now() + self.latency().unwrap_or_default();
+        let tick = self.clock.server_tick(arrival).unwrap_or(0);
+        self.send(ClientMsg::Move(MoveInput { seq, movement, tick }))
     }
 
-    pub fn pong(&mut self) -> Result<(), ConnectionError> {
-        self.send(ClientMsg::Pong)
+    pub fn pong(&mut self, ping: ClockSync) -> Result<()> {
+        self.send(ClientMsg::Pong(ping.time_ms))
     }
 
-    fn send(&mut self, msg: ClientMsg) -> Result<(), ConnectionError> {
-        let msg = serialize(&msg).unwrap();
-        let packet = Packet::reliable_ordered(self.server_addr, msg, None);
-        self.sender.
-            send(packet).
-            map_err(|_| ConnectionError::FailedToSend(String::from("Could not send")))
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        self.clock.round_trip_time()
     }
 
-    pub fn receive(&mut self) -> Option<ServerMsg> {
-        // This is cheating, error is treated as a nonexistant message.
-        let pkt = self.receiver.try_recv().ok()?;
+    // Estimated one way latency to the server.
+    pub fn latency(&self) -> Option<Duration> {
+        self.clock.latency()
+    }
 
-        match pkt {
-            SocketEvent::Packet(pkt) => {
-                deserialize::<ServerMsg>(pkt.payload()).ok()
-            }
+    // The tick the server is estimated to be on right now.
+    pub fn server_tick(&self) -> Option<u32> {
+        self.clock.server_tick(Instant::now())
+    }
 
-            SocketEvent::Timeout(_) => {
-                println!("Timeout");
-                Some(ServerMsg::Timeout)
-            }
+    fn send(&mut self, msg: ClientMsg) -> Result<()> {
+        let channel = Channel::for_client_msg(&msg);
+        self.transport
+            .send(self.server_addr, encode_client_msg(&msg), channel)
+    }
 
-            SocketEvent::Connect(_) => {
-                println!("Connected");
-                Some(ServerMsg::Connect)
-            }
+    // Turns a full state or a delta into a whole state, and lets the server
+    // know we got it.
+    fn on_state(&mut self, snapshot: Snapshot, ack: InputAck) -> Option<ServerMsg> {
+        let state = self.decoder.decode(snapshot)?;
 
-            SocketEvent::Disconnect(_) => {
-                println!("Disconnected");
-                Some(ServerMsg::Disconnect)
+        self.clock.on_tick(state.ticks, Instant::now());
+        if self.send(ClientMsg::AckState(state.ticks)).is_err() {
+            println!("Could not acknowledge state");
+        }
+
+        Some(ServerMsg::State(state, ack))
+    }
+
+    // None when there is nothing more to receive right now.
+    pub fn receive(&mut self) -> Option<Result<ServerMsg>> {
+        loop {
+            let msg = match self.transport.recv()? {
+                TransportEvent::Packet(addr, payload, _) if addr == self.server_addr => {
+                    match decode_server_msg(&payload) {
+                        Ok(ServerMsg::Reject(reason)) => return Some(Err(Error::Protocol(reason))),
+                        Ok(msg) => msg,
+
+                        // A server on another version might not know how to
+                        // tell us, so we notice ourselves.
+                        Err(DecodeError::WrongVersion(server_version)) => {
+                            let reason = RejectReason::UnsupportedVersion { server_version };
+                            return Some(Err(Error::Protocol(reason)));
+                        }
+                        Err(err) => return Some(Err(Error::Decode(err))),
+                    }
+                }
+
+                TransportEvent::Timeout(addr) | TransportEvent::Disconnect(addr)
+                    if addr == self.server_addr =>
+                {
+                    return Some(Err(Error::Timeout(self.server_addr)));
+                }
+
+                TransportEvent::Connect(addr) if addr == self.server_addr => {
+                    println!("Connected");
+                    continue;
+                }
+
+                // Only the server has anything to tell us.
+                _ => continue,
+            };
+
+            // Deltas against states we don't have, and states older than the
+            // newest one, are skipped.
+            let msg = match msg {
+                ServerMsg::State(state, ack) => self.on_state(Snapshot::Full(state), ack),
+                ServerMsg::StateDelta(delta, ack) => self.on_state(Snapshot::Delta(delta), ack),
+                msg => Some(msg),
+            };
+
+            if let Some(msg) = msg {
+                match msg {
+                    ServerMsg::Ping(clock) => self.clock.on_ping(clock, Instant::now()),
+                    ServerMsg::Start(_, token) => {
+                        self.clock.reset_tick();
+                        self.decoder.reset();
+                        self.session = Some(token);
+                    }
+                    ServerMsg::Watching(..) => self.decoder.reset(),
+                    ServerMsg::MatchOver(_) | ServerMsg::Abort => self.session = None,
+                    _ => {}
+                }
+                return Some(Ok(msg));
             }
         }
     }
@@ -92,10 +214,167 @@ impl ClientConnection {
 
 #[cfg(test)]
 mod client_connection_tests {
+    use crate::codec::{encode_server_msg, decode_client_msg};
+    use crate::net_messages::{PlayerOrder, SessionToken};
+    use crate::pong_state::PongState;
+    use crate::transport::{MemoryNetwork, MemoryTransport};
     use super::*;
 
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    fn connect(server: &MemoryTransport, client: Box<MemoryTransport>) -> ClientConnection {
+        ClientConnection::with_transport(server.local_addr(), client, Profile::default()).unwrap()
+    }
+
     #[test]
     fn test_connect_attempts_to_greet_server() {
-        ClientConnection::connect("127.0.0.1:64646").unwrap();
+        ClientConnection::connect("127.0.0.1:64646", LinkConditions::default(), Profile::default())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_greets_server_and_receives_its_messages() {
+        let network = MemoryNetwork::new();
+        let client_addr = addr("127.0.0.1:45456");
+        let mut server = network.bind(addr("127.0.0.1:6666"));
+        let client = Box::new(network.bind(client_addr));
+        let mut connection = connect(&server, client);
+
+        match server.recv() {
+            Some(TransportEvent::Packet(src, payload, _)) => {
+                assert_eq!(src, client_addr);
+                assert_eq!(decode_client_msg(&payload), Ok(ClientMsg::Hi(Hello::new())));
+            }
+            event => panic!("Expected a hi, got {:?}", event),
+        }
+
+        let start = ServerMsg::Start(PlayerOrder::P2, SessionToken(7));
+        server.send(client_addr, encode_server_msg(&start), Channel::Control).unwrap();
+        assert!(matches!(connection.receive(), Some(Ok(ServerMsg::Start(PlayerOrder::P2, _)))));
+        assert!(connection.receive().is_none());
+    }
+
+    #[test]
+    fn test_rejections_and_timeouts_are_errors() {
+        let network = MemoryNetwork::new();
+        let server_addr = addr("127.0.0.1:6666");
+        let client_addr = addr("127.0.0.1:45456");
+        let mut server = network.bind(server_addr);
+        let client = Box::new(network.bind(client_addr));
+        let mut connection = ClientConnection::with_transport(server_addr, client, Profile::default())
+            .unwrap();
+
+        let reason = RejectReason::UnsupportedVersion { server_version: 1 };
+        server.send(client_addr, encode_server_msg(&ServerMsg::Reject(reason)), Channel::Control).unwrap();
+        network.deliver(client_addr, TransportEvent::Timeout(server_addr));
+
+        assert!(matches!(connection.receive(), Some(Err(Error::Protocol(_)))));
+        assert!(matches!(connection.receive(), Some(Err(Error::Timeout(_)))));
+    }
+
+    #[test]
+    fn test_only_listens_to_the_server() {
+        let network = MemoryNetwork::new();
+        let client_addr = addr("127.0.0.1:45456");
+        let mut stranger = network.bind(addr("127.0.0.1:7777"));
+        let server = network.bind(addr("127.0.0.1:6666"));
+        let client = Box::new(network.bind(client_addr));
+        let mut connection = connect(&server, client);
+
+        stranger.send(client_addr, encode_server_msg(&ServerMsg::Abort), Channel::Control).unwrap();
+        network.deliver(client_addr, TransportEvent::Timeout(stranger.local_addr()));
+
+        assert!(connection.receive().is_none());
+    }
+
+    #[test]
+    fn test_resumes_the_match_it_was_started_in() {
+        let network = MemoryNetwork::new();
+        let client_addr = addr("127.0.0.1:45456");
+        let mut server = network.bind(addr("127.0.0.1:6666"));
+        let client = Box::new(network.bind(client_addr));
+        let mut connection = connect(&server, client);
+        server.recv();
+
+        assert!(!connection.resume().unwrap());
+
+        let start = ServerMsg::Start(PlayerOrder::P1, SessionToken(7));
+        server.send(client_addr, encode_server_msg(&start), Channel::Control).unwrap();
+        connection.receive();
+        assert!(connection.resume().unwrap());
+
+        match server.recv() {
+            Some(TransportEvent::Packet(_, payload, _)) => assert_eq!(
+                decode_client_msg(&payload),
+                Ok(ClientMsg::Resume(Hello::new(), SessionToken(7)))
+            ),
+            event => panic!("Expected a resume, got {:?}", event),
+        }
+
+        server.send(client_addr, encode_server_msg(&ServerMsg::Abort), Channel::Control).unwrap();
+        connection.receive();
+        assert!(!connection.resume().unwrap());
+    }
+
+    #[test]
+    fn test_late_states_are_skipped() {
+        let network = MemoryNetwork::new();
+        let client_addr = addr("127.0.0.1:45456");
+        let mut server = network.bind(addr("127.0.0.1:6666"));
+        let client = Box::new(network.bind(client_addr));
+        let mut connection = connect(&server, client);
+
+        let older = PongState { ticks: 10, ..PongState::new() };
+        let newer = PongState { ticks: 12, ..PongState::new() };
+        for state in &[newer, older] {
+            let msg = ServerMsg::State(*state, InputAck::default());
+            server.send(client_addr, encode_server_msg(&msg), Channel::State).unwrap();
+        }
+
+        assert!(matches!(connection.receive(), Some(Ok(ServerMsg::State(state, _))) if state == newer));
+        assert!(connection.receive().is_none());
+    }
+
+    #[test]
+    fn test_reconnect_says_hi_when_not_in_a_match() {
+        let network = MemoryNetwork::new();
+        let mut server = network.bind(addr("127.0.0.1:6666"));
+        let client = Box::new(network.bind(addr("127.0.0.1:45456")));
+        let mut connection = connect(&server, client);
+        server.recv();
+
+        connection.reconnect().unwrap();
+
+        match server.recv() {
+            Some(TransportEvent::Packet(_, payload, _)) => {
+                assert_eq!(decode_client_msg(&payload), Ok(ClientMsg::Hi(Hello::new())));
+            }
+            event => panic!("Expected a hi, got {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_spectators_ask_to_watch_under_their_name() {
+        let network = MemoryNetwork::new();
+        let mut server = network.bind(addr("127.0.0.1:6666"));
+        let client = Box::new(network.bind(addr("127.0.0.1:45456")));
+        let profile = Profile { name: "Carol".to_string(), spectator: true };
+        let mut connection =
+            ClientConnection::with_transport(server.local_addr(), client, profile).unwrap();
+
+        assert!(connection.is_spectator());
+        connection.reconnect().unwrap();
+
+        for _ in 0..2 {
+            match server.recv() {
+                Some(TransportEvent::Packet(_, payload, _)) => assert_eq!(
+                    decode_client_msg(&payload),
+                    Ok(ClientMsg::Spectate(Hello::with_name("Carol")))
+                ),
+                event => panic!("Expected a spectate, got {:?}", event),
+            }
+        }
     }
 }
@@ -0,0 +1,146 @@
+// The client's idea of the server clock.
+//
+// The server measures the round trip time to each client and sends it along
+// with its clock in every ping. From that the client estimates the latency,
+// the server time and which tick the server is on right now.
+
+use crate::game_constants::MS_PER_UPDATE;
+use crate::net_messages::ClockSync;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+pub struct ServerClock {
+    // Smoothed round trip time in ms.
+    rtt_ms: Option<u64>,
+
+    // Server time in ms at a local instant.
+    time_sync: Option<(u64, Instant)>,
+
+    // Server tick at a local instant.
+    tick_sync: Option<(u32, Instant)>,
+}
+
+impl ServerClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn on_ping(&mut self, clock: ClockSync, received: Instant) {
+        if let Some(rtt) = clock.rtt_ms {
+            // Smooth out the samples a bit, one slow pong shouldn't throw us off.
+            self.rtt_ms = Some(match self.rtt_ms {
+                Some(old) => (old * 7 + rtt) / 8,
+                None => rtt,
+            });
+        }
+
+        // The ping was sent about half a round trip ago.
+        let one_way = self.latency().unwrap_or_default().as_millis() as u64;
+        self.time_sync = Some((clock.time_ms + one_way, received));
+
+        if clock.tick > 0 {
+            self.on_tick(clock.tick, received);
+        }
+    }
+
+    // Called for every state from the server, which are stamped with a tick.
+    pub fn on_tick(&mut self, tick: u32, received: Instant) {
+        let one_way_ticks = self.latency().unwrap_or_default().as_millis() as u64 / MS_PER_UPDATE;
+        let tick = tick + one_way_ticks as u32;
+
+        // Late or reordered messages shouldn't move the clock backwards.
+        if let Some(known) = self.server_tick(received) {
+            if tick < known {
+                return;
+            }
+        }
+        self.tick_sync = Some((tick, received));
+    }
+
+    // Ticks start over with every match.
+    pub fn reset_tick(&mut self) {
+        self.tick_sync = None;
+    }
+
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        self.rtt_ms.map(Duration::from_millis)
+    }
+
+    // One way, i e half a round trip.
+    pub fn latency(&self) -> Option<Duration> {
+        self.rtt_ms.map(|rtt| Duration::from_millis(rtt / 2))
+    }
+
+    pub fn server_time_ms(&self, now: Instant) -> Option<u64> {
+        let (time_ms, at) = self.time_sync?;
+        Some(time_ms + now.saturating_duration_since(at).as_millis() as u64)
+    }
+
+    pub fn server_tick(&self, now: Instant) -> Option<u32> {
+        let (tick, at) = self.tick_sync?;
+        let elapsed = now.saturating_duration_since(at).as_millis() as u64;
+        Some(tick + (elapsed / MS_PER_UPDATE) as u32)
+    }
+}
+
+#[cfg(test)]
+mod clock_tests {
+    use super::*;
+
+    #[test]
+    fn test_nothing_is_known_before_the_first_ping() {
+        let clock = ServerClock::new();
+        let now = Instant::now();
+
+        assert_eq!(clock.round_trip_time(), None);
+        assert_eq!(clock.server_time_ms(now), None);
+        assert_eq!(clock.server_tick(now), None);
+    }
+
+    #[test]
+    fn test_ping_gives_latency_and_server_time() {
+        let mut clock = ServerClock::new();
+        let now = Instant::now();
+
+        clock.on_ping(ClockSync { tick: 0, time_ms: 1000, rtt_ms: Some(40) }, now);
+
+        assert_eq!(clock.round_trip_time(), Some(Duration::from_millis(40)));
+        assert_eq!(clock.latency(), Some(Duration::from_millis(20)));
+        assert_eq!(clock.server_time_ms(now), Some(1020));
+        assert_eq!(clock.server_time_ms(now + Duration::from_millis(100)), Some(1120));
+    }
+
+    #[test]
+    fn test_round_trip_time_is_smoothed() {
+        let mut clock = ServerClock::new();
+        let now = Instant::now();
+
+        clock.on_ping(ClockSync { tick: 0, time_ms: 0, rtt_ms: Some(40) }, now);
+        clock.on_ping(ClockSync { tick: 0, time_ms: 0, rtt_ms: Some(120) }, now);
+
+        assert_eq!(clock.round_trip_time(), Some(Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn test_server_tick_moves_with_time() {
+        let mut clock = ServerClock::new();
+        let now = Instant::now();
+
+        clock.on_tick(100, now);
+        assert_eq!(clock.server_tick(now), Some(100));
+
+        let later = now + Duration::from_millis(MS_PER_UPDATE * 10);
+        assert_eq!(clock.server_tick(later), Some(110));
+    }
+
+    #[test]
+    fn test_old_ticks_are_ignored() {
+        let mut clock = ServerClock::new();
+        let now = Instant::now();
+
+        clock.on_tick(100, now);
+        clock.on_tick(90, now);
+
+        assert_eq!(clock.server_tick(now), Some(100));
+    }
+}
@@ -0,0 +1,746 @@
+// A compact wire format for ServerMsg and ClientMsg.
+//
+// Messages are packed bit by bit instead of going through bincode. Every
+// packet starts with the protocol version, followed by a 4 bit message tag.
+// Positions and velocities are quantized to the few bits the game field
+// needs, and counters like ticks and scores use a variable length encoding so
+// small numbers stay small.
+//
+// The handshake messages (ClientMsg::Hi, ServerMsg::Accept and
+// ServerMsg::Reject) are decoded whatever version the packet says, so that
+// peers on different versions can still tell each other why they can't play.
+// Their tags and layouts must never change. Newer versions may only add fields
+// at the end, which older ones never get to.
+
+use crate::delta::StateDelta;
+use crate::net_messages::{
+    ClientMsg,
+    ServerMsg,
+    PlayerOrder,
+    MatchResult,
+    MoveInput,
+    InputAck,
+    ClockSync,
+    Capabilities,
+    Hello,
+    RejectReason,
+    SessionToken,
+    PROTOCOL_VERSION,
+};
+use crate::pong_state::{PongState, PlayerMovement};
+use crate::rng::Rng;
+use std::convert::TryFrom;
+
+const TAG_BITS: u32 = 4;
+
+const CLIENT_HI_TAG: u8 = 0;
+const SERVER_ACCEPT_TAG: u8 = 10;
+const SERVER_REJECT_TAG: u8 = 11;
+
+// The first version whose hellos end with the player name.
+const NAMES_SINCE_VERSION: u8 = 6;
+
+// Positions fit in [-1024, 1023], which covers the field with a good margin.
+const POSITION_BITS: u32 = 11;
+
+// Velocities fit in [-128, 127].
+const VELOCITY_BITS: u32 = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DecodeError {
+    // The packet ended before the message did.
+    Truncated,
+
+    // Sent by someone speaking another version of the protocol.
+    WrongVersion(u8),
+
+    UnknownTag(u8),
+
+    // A field holds something it never should, e g a move without movement.
+    InvalidValue,
+
+    // A number too big for its field.
+    Overflow,
+}
+
+pub fn encode_server_msg(msg: &ServerMsg) -> Vec<u8> {
+    let mut w = BitWriter::new();
+    w.write(PROTOCOL_VERSION as u64, 8);
+
+    match msg {
+        ServerMsg::Hi => w.write(0, TAG_BITS),
+        ServerMsg::Ping(clock) => {
+            w.write(1, TAG_BITS);
+            write_clock_sync(&mut w, clock);
+        }
+        // Tags 2 to 4 used to be timeouts, disconnects and connects, which
+        // are noticed by each side on their own rather than sent.
+        ServerMsg::Start(order, session) => {
+            w.write(5, TAG_BITS);
+            write_player_order(&mut w, *order);
+            w.write(session.0, 64);
+        }
+        ServerMsg::State(state, ack) => {
+            w.write(6, TAG_BITS);
+            write_pong_state(&mut w, state);
+            write_input_ack(&mut w, ack);
+        }
+        ServerMsg::StateDelta(delta, ack) => {
+            w.write(7, TAG_BITS);
+            write_state_delta(&mut w, delta);
+            write_input_ack(&mut w, ack);
+        }
+        ServerMsg::MatchOver(result) => {
+            w.write(8, TAG_BITS);
+            write_player_order(&mut w, result.winner);
+            w.write_varint(result.p1_score as u64);
+            w.write_varint(result.p2_score as u64);
+        }
+        ServerMsg::Abort => w.write(9, TAG_BITS),
+        ServerMsg::Paused(seconds_left) => {
+            w.write(12, TAG_BITS);
+            w.write_varint(*seconds_left as u64);
+        }
+        ServerMsg::Queued(position) => {
+            w.write(13, TAG_BITS);
+            w.write_varint(*position as u64);
+        }
+        ServerMsg::Watching(p1_name, p2_name) => {
+            w.write(14, TAG_BITS);
+            write_name(&mut w, p1_name);
+            write_name(&mut w, p2_name);
+        }
+        ServerMsg::Accept(capabilities) => {
+            w.write(SERVER_ACCEPT_TAG as u64, TAG_BITS);
+            write_capabilities(&mut w, *capabilities);
+        }
+        ServerMsg::Reject(reason) => {
+            w.write(SERVER_REJECT_TAG as u64, TAG_BITS);
+            write_reject_reason(&mut w, *reason);
+        }
+    }
+
+    w.finish()
+}
+
+pub fn decode_server_msg(bytes: &[u8]) -> Result<ServerMsg, DecodeError> {
+    let mut r = BitReader::new(bytes);
+    let tag = read_header(&mut r, &[SERVER_ACCEPT_TAG, SERVER_REJECT_TAG])?;
+
+    let msg = match tag {
+        0 => ServerMsg::Hi,
+        1 => ServerMsg::Ping(read_clock_sync(&mut r)?),
+        5 => ServerMsg::Start(read_player_order(&mut r)?, SessionToken(r.read(64)?)),
+        6 => ServerMsg::State(read_pong_state(&mut r)?, read_input_ack(&mut r)?),
+        7 => ServerMsg::StateDelta(read_state_delta(&mut r)?, read_input_ack(&mut r)?),
+        8 => ServerMsg::MatchOver(MatchResult {
+            winner: read_player_order(&mut r)?,
+            p1_score: r.read_varint_u32()?,
+            p2_score: r.read_varint_u32()?,
+        }),
+        9 => ServerMsg::Abort,
+        12 => ServerMsg::Paused(r.read_varint_u32()?),
+        13 => ServerMsg::Queued(r.read_varint_u32()?),
+        14 => ServerMsg::Watching(read_name(&mut r)?, read_name(&mut r)?),
+        SERVER_ACCEPT_TAG => ServerMsg::Accept(read_capabilities(&mut r)?),
+        SERVER_REJECT_TAG => ServerMsg::Reject(read_reject_reason(&mut r)?),
+        _ => return Err(DecodeError::UnknownTag(tag)),
+    };
+    Ok(msg)
+}
+
+pub fn encode_client_msg(msg: &ClientMsg) -> Vec<u8> {
+    let mut w = BitWriter::new();
+    w.write(PROTOCOL_VERSION as u64, 8);
+
+    match msg {
+        ClientMsg::Hi(hello) => {
+            w.write(CLIENT_HI_TAG as u64, TAG_BITS);
+            write_hello(&mut w, hello);
+        }
+        ClientMsg::Pong(time_ms) => {
+            w.write(1, TAG_BITS);
+            w.write_varint(*time_ms);
+        }
+        // Tags 2 to 4 used to be timeouts, connects and disconnects. Only the
+        // transport gets to tell about those, anyone could send them.
+        ClientMsg::Timeout | ClientMsg::Connect | ClientMsg::Disconnect => {
+            unreachable!("{:?} comes from the transport and is never sent", msg)
+        }
+        ClientMsg::Move(input) => {
+            w.write(5, TAG_BITS);
+            w.write_varint(input.seq as u64);
+            write_movement(&mut w, Some(input.movement));
+            w.write_varint(input.tick as u64);
+        }
+        ClientMsg::AckState(tick) => {
+            w.write(6, TAG_BITS);
+            w.write_varint(*tick as u64);
+        }
+        ClientMsg::Resume(hello, session) => {
+            w.write(7, TAG_BITS);
+            write_hello(&mut w, hello);
+            w.write(session.0, 64);
+        }
+        ClientMsg::Spectate(hello) => {
+            w.write(8, TAG_BITS);
+            write_hello(&mut w, hello);
+        }
+    }
+
+    w.finish()
+}
+
+pub fn decode_client_msg(bytes: &[u8]) -> Result<ClientMsg, DecodeError> {
+    let mut r = BitReader::new(bytes);
+    let tag = read_header(&mut r, &[CLIENT_HI_TAG])?;
+
+    let msg = match tag {
+        CLIENT_HI_TAG => ClientMsg::Hi(read_hello(&mut r)?),
+        1 => ClientMsg::Pong(r.read_varint()?),
+        5 => {
+            let seq = r.read_varint_u32()?;
+
+            // A move is never "no movement"
+            let movement = read_movement(&mut r)?.ok_or(DecodeError::InvalidValue)?;
+            let tick = r.read_varint_u32()?;
+            ClientMsg::Move(MoveInput { seq, movement, tick })
+        }
+        6 => ClientMsg::AckState(r.read_varint_u32()?),
+        7 => ClientMsg::Resume(read_hello(&mut r)?, SessionToken(r.read(64)?)),
+        8 => ClientMsg::Spectate(read_hello(&mut r)?),
+        _ => return Err(DecodeError::UnknownTag(tag)),
+    };
+    Ok(msg)
+}
+
+// Returns the message tag. Packets from other versions are only let through
+// for the handshake tags.
+fn read_header(r: &mut BitReader, handshake_tags: &[u8]) -> Result<u8, DecodeError> {
+    let version = r.read(8)? as u8;
+    let tag = r.read(TAG_BITS)? as u8;
+    if version != PROTOCOL_VERSION && !handshake_tags.contains(&tag) {
+        return Err(DecodeError::WrongVersion(version));
+    }
+    Ok(tag)
+}
+
+fn write_hello(w: &mut BitWriter, hello: &Hello) {
+    w.write(hello.version as u64, 8);
+    write_capabilities(w, hello.capabilities);
+    if hello.version >= NAMES_SINCE_VERSION {
+        write_name(w, &hello.name);
+    }
+}
+
+fn read_hello(r: &mut BitReader) -> Result<Hello, DecodeError> {
+    let version = r.read(8)? as u8;
+    let capabilities = read_capabilities(r)?;
+    let name = if version >= NAMES_SINCE_VERSION { read_name(r)? } else { String::new() };
+    Ok(Hello { version, capabilities, name })
+}
+
+// The length in bytes, then the bytes.
+fn write_name(w: &mut BitWriter, name: &str) {
+    w.write_varint(name.len() as u64);
+    for &byte in name.as_bytes() {
+        w.write(byte as u64, 8);
+    }
+}
+
+// Bytes that aren't UTF-8 are replaced rather than failing the whole message.
+fn read_name(r: &mut BitReader) -> Result<String, DecodeError> {
+    let len = r.read_varint()?;
+    let mut bytes = vec![];
+    for _ in 0..len {
+        bytes.push(r.read(8)? as u8);
+    }
+
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// One bit per capability. Bits this version doesn't know about are ignored,
+// so newer clients can offer more.
+fn write_capabilities(w: &mut BitWriter, capabilities: Capabilities) {
+    let mut bits = 0;
+    if capabilities.state_deltas {
+        bits |= 1;
+    }
+    w.write_varint(bits);
+}
+
+fn read_capabilities(r: &mut BitReader) -> Result<Capabilities, DecodeError> {
+    let bits = r.read_varint()?;
+    Ok(Capabilities { state_deltas: bits & 1 != 0 })
+}
+
+fn write_reject_reason(w: &mut BitWriter, reason: RejectReason) {
+    match reason {
+        RejectReason::UnsupportedVersion { server_version } => {
+            w.write(0, TAG_BITS);
+            w.write(server_version as u64, 8);
+        }
+    }
+}
+
+fn read_reject_reason(r: &mut BitReader) -> Result<RejectReason, DecodeError> {
+    match r.read(TAG_BITS)? as u8 {
+        0 => Ok(RejectReason::UnsupportedVersion { server_version: r.read(8)? as u8 }),
+        tag => Err(DecodeError::UnknownTag(tag)),
+    }
+}
+
+fn write_player_order(w: &mut BitWriter, order: PlayerOrder) {
+    w.write(match order { PlayerOrder::P1 => 0, PlayerOrder::P2 => 1 }, 1);
+}
+
+fn read_player_order(r: &mut BitReader) -> Result<PlayerOrder, DecodeError> {
+    Ok(if r.read(1)? == 0 { PlayerOrder::P1 } else { PlayerOrder::P2 })
+}
+
+fn write_movement(w: &mut BitWriter, movement: Option<PlayerMovement>) {
+    let value = match movement {
+        None => 0,
+        Some(PlayerMovement::Up) => 1,
+        Some(PlayerMovement::Down) => 2,
+        Some(PlayerMovement::Still) => 3,
+    };
+    w.write(value, 2);
+}
+
+fn read_movement(r: &mut BitReader) -> Result<Option<PlayerMovement>, DecodeError> {
+    Ok(match r.read(2)? {
+        1 => Some(PlayerMovement::Up),
+        2 => Some(PlayerMovement::Down),
+        3 => Some(PlayerMovement::Still),
+        _ => None,
+    })
+}
+
+fn write_pair(w: &mut BitWriter, (x, y): (i32, i32), bits: u32) {
+    w.write_signed(x, bits);
+    w.write_signed(y, bits);
+}
+
+fn read_pair(r: &mut BitReader, bits: u32) -> Result<(i32, i32), DecodeError> {
+    Ok((r.read_signed(bits)?, r.read_signed(bits)?))
+}
+
+fn write_pong_state(w: &mut BitWriter, state: &PongState) {
+    w.write_signed(state.p1, POSITION_BITS);
+    write_movement(w, state.p1_move);
+    w.write_signed(state.p2, POSITION_BITS);
+    write_movement(w, state.p2_move);
+    write_pair(w, state.ball, POSITION_BITS);
+    write_pair(w, state.ball_vel, VELOCITY_BITS);
+    w.write_varint(state.p1_score as u64);
+    w.write_varint(state.p2_score as u64);
+    w.write_varint(state.points_to_win as u64);
+    write_player_order(w, state.serving);
+    w.write_varint(state.serve_delay as u64);
+    w.write_varint(state.ticks as u64);
+    w.write(state.rng.state(), 64);
+}
+
+fn read_pong_state(r: &mut BitReader) -> Result<PongState, DecodeError> {
+    Ok(PongState {
+        p1: r.read_signed(POSITION_BITS)?,
+        p1_move: read_movement(r)?,
+        p2: r.read_signed(POSITION_BITS)?,
+        p2_move: read_movement(r)?,
+        ball: read_pair(r, POSITION_BITS)?,
+        ball_vel: read_pair(r, VELOCITY_BITS)?,
+        p1_score: r.read_varint_u32()?,
+        p2_score: r.read_varint_u32()?,
+        points_to_win: r.read_varint_u32()?,
+        serving: read_player_order(r)?,
+        serve_delay: r.read_varint_u32()?,
+        ticks: r.read_varint_u32()?,
+        rng: Rng::new(r.read(64)?),
+    })
+}
+
+// Every field of a delta is preceded by a bit telling whether it's there.
+fn write_state_delta(w: &mut BitWriter, delta: &StateDelta) {
+    w.write_varint(delta.base_tick as u64);
+    w.write_varint(delta.ticks as u64);
+
+    w.write_option(delta.p1, |w, p1| w.write_signed(p1, POSITION_BITS));
+    w.write_option(delta.p1_move, write_movement);
+    w.write_option(delta.p2, |w, p2| w.write_signed(p2, POSITION_BITS));
+    w.write_option(delta.p2_move, write_movement);
+    w.write_option(delta.ball, |w, ball| write_pair(w, ball, POSITION_BITS));
+    w.write_option(delta.ball_vel, |w, vel| write_pair(w, vel, VELOCITY_BITS));
+    w.write_option(delta.p1_score, |w, score| w.write_varint(score as u64));
+    w.write_option(delta.p2_score, |w, score| w.write_varint(score as u64));
+    w.write_option(delta.points_to_win, |w, points| w.write_varint(points as u64));
+    w.write_option(delta.serving, write_player_order);
+    w.write_option(delta.serve_delay, |w, delay| w.write_varint(delay as u64));
+    w.write_option(delta.rng, |w, rng| w.write(rng.state(), 64));
+}
+
+fn read_state_delta(r: &mut BitReader) -> Result<StateDelta, DecodeError> {
+    Ok(StateDelta {
+        base_tick: r.read_varint_u32()?,
+        ticks: r.read_varint_u32()?,
+        p1: r.read_option(|r| r.read_signed(POSITION_BITS))?,
+        p1_move: r.read_option(read_movement)?,
+        p2: r.read_option(|r| r.read_signed(POSITION_BITS))?,
+        p2_move: r.read_option(read_movement)?,
+        ball: r.read_option(|r| read_pair(r, POSITION_BITS))?,
+        ball_vel: r.read_option(|r| read_pair(r, VELOCITY_BITS))?,
+        p1_score: r.read_option(|r| r.read_varint_u32())?,
+        p2_score: r.read_option(|r| r.read_varint_u32())?,
+        points_to_win: r.read_option(|r| r.read_varint_u32())?,
+        serving: r.read_option(read_player_order)?,
+        serve_delay: r.read_option(|r| r.read_varint_u32())?,
+        rng: r.read_option(|r| Ok(Rng::new(r.read(64)?)))?,
+    })
+}
+
+fn write_input_ack(w: &mut BitWriter, ack: &InputAck) {
+    w.write_varint(ack.seq as u64);
+    w.write_varint(ack.ticks as u64);
+}
+
+fn read_input_ack(r: &mut BitReader) -> Result<InputAck, DecodeError> {
+    Ok(InputAck { seq: r.read_varint_u32()?, ticks: r.read_varint_u32()? })
+}
+
+fn write_clock_sync(w: &mut BitWriter, clock: &ClockSync) {
+    w.write_varint(clock.tick as u64);
+    w.write_varint(clock.time_ms);
+    w.write_option(clock.rtt_ms, BitWriter::write_varint);
+}
+
+fn read_clock_sync(r: &mut BitReader) -> Result<ClockSync, DecodeError> {
+    Ok(ClockSync {
+        tick: r.read_varint_u32()?,
+        time_ms: r.read_varint()?,
+        rtt_ms: r.read_option(BitReader::read_varint)?,
+    })
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+
+    // How many bits of the last byte are used, 8 means it's full.
+    used: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self { bytes: vec![], used: 8 }
+    }
+
+    // Writes the lowest `bits` bits of value, most significant first.
+    fn write(&mut self, value: u64, bits: u32) {
+        for i in (0..bits).rev() {
+            if self.used == 8 {
+                self.bytes.push(0);
+                self.used = 0;
+            }
+
+            let bit = ((value >> i) & 1) as u8;
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= bit << (7 - self.used);
+            self.used += 1;
+        }
+    }
+
+    // Values that don't fit are clamped. Nothing we send should ever need
+    // that, so debug builds complain.
+    fn write_signed(&mut self, value: i32, bits: u32) {
+        let offset = 1i64 << (bits - 1);
+        debug_assert!(
+            (-offset..offset).contains(&(value as i64)),
+            "{} does not fit in {} bits",
+            value,
+            bits
+        );
+        let clamped = (value as i64).max(-offset).min(offset - 1);
+        self.write((clamped + offset) as u64, bits);
+    }
+
+    // 7 bits at a time, each group preceded by a bit telling if more follow.
+    fn write_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.write(1, 1);
+            self.write(value & 0x7f, 7);
+            value >>= 7;
+        }
+        self.write(0, 1);
+        self.write(value, 7);
+    }
+
+    fn write_option<T>(&mut self, value: Option<T>, write: impl Fn(&mut Self, T)) {
+        match value {
+            Some(value) => {
+                self.write(1, 1);
+                write(self, value);
+            }
+            None => self.write(0, 1),
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+
+    // Index of the next bit to read.
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    fn read(&mut self, bits: u32) -> Result<u64, DecodeError> {
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = self.bytes.get(self.pos / 8).ok_or(DecodeError::Truncated)?;
+            let bit = (byte >> (7 - self.pos % 8)) & 1;
+            value = (value << 1) | bit as u64;
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+
+    fn read_signed(&mut self, bits: u32) -> Result<i32, DecodeError> {
+        let offset = 1i64 << (bits - 1);
+        Ok((self.read(bits)? as i64 - offset) as i32)
+    }
+
+    fn read_varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let more = self.read(1)? == 1;
+            let group = self.read(7)?;
+
+            // Don't let garbage shift past the end of a u64.
+            if shift >= 64 || (shift == 63 && group > 1) {
+                return Err(DecodeError::Overflow);
+            }
+            value |= group << shift;
+            shift += 7;
+
+            if !more {
+                return Ok(value);
+            }
+        }
+    }
+
+    // Counters, scores and the like are u32 in the game.
+    fn read_varint_u32(&mut self) -> Result<u32, DecodeError> {
+        u32::try_from(self.read_varint()?).map_err(|_| DecodeError::Overflow)
+    }
+
+    fn read_option<T>(
+        &mut self,
+        read: impl Fn(&mut Self) -> Result<T, DecodeError>
+    ) -> Result<Option<T>, DecodeError> {
+        if self.read(1)? == 1 {
+            Ok(Some(read(self)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod codec_tests {
+    use super::*;
+
+    fn played_state() -> PongState {
+        let mut state = PongState::new_seeded(11, 1234);
+        for _ in 0..500 {
+            state.tick(Some(PlayerMovement::Down), Some(PlayerMovement::Up));
+        }
+        state
+    }
+
+    fn server_msgs() -> Vec<ServerMsg> {
+        let state = played_state();
+        let ack = InputAck { seq: 17, ticks: 300 };
+        let mut next = state;
+        next.tick(None, Some(PlayerMovement::Still));
+
+        vec![
+            ServerMsg::Hi,
+            ServerMsg::Ping(ClockSync { tick: 1000, time_ms: 123_456_789, rtt_ms: Some(35) }),
+            ServerMsg::Ping(ClockSync { tick: 0, time_ms: 0, rtt_ms: None }),
+            ServerMsg::Start(PlayerOrder::P2, SessionToken(u64::MAX - 3)),
+            ServerMsg::State(state, ack),
+            ServerMsg::StateDelta(StateDelta::between(&state, &next), ack),
+            ServerMsg::MatchOver(MatchResult { winner: PlayerOrder::P1, p1_score: 12, p2_score: 10 }),
+            ServerMsg::Abort,
+            ServerMsg::Paused(10),
+            ServerMsg::Queued(1),
+            ServerMsg::Queued(300),
+            ServerMsg::Watching("Alice".to_string(), String::new()),
+            ServerMsg::Accept(Capabilities::all()),
+            ServerMsg::Accept(Capabilities::default()),
+            ServerMsg::Reject(RejectReason::UnsupportedVersion { server_version: 7 }),
+        ]
+    }
+
+    fn client_msgs() -> Vec<ClientMsg> {
+        vec![
+            ClientMsg::Hi(Hello::new()),
+            ClientMsg::Hi(Hello::with_name("Bob")),
+            ClientMsg::Hi(Hello { version: 9, capabilities: Capabilities::default(), name: "åke".to_string() }),
+            ClientMsg::Pong(u64::MAX),
+            ClientMsg::Move(MoveInput { seq: 3, movement: PlayerMovement::Down, tick: 700 }),
+            ClientMsg::AckState(4000),
+            ClientMsg::Resume(Hello::new(), SessionToken(0x1234_5678_9abc_def0)),
+            ClientMsg::Spectate(Hello::with_name("Carol")),
+        ]
+    }
+
+    #[test]
+    fn test_server_msgs_survive_round_trip() {
+        for msg in server_msgs() {
+            assert_eq!(decode_server_msg(&encode_server_msg(&msg)), Ok(msg));
+        }
+    }
+
+    #[test]
+    fn test_client_msgs_survive_round_trip() {
+        for msg in client_msgs() {
+            assert_eq!(decode_client_msg(&encode_client_msg(&msg)), Ok(msg));
+        }
+    }
+
+    #[test]
+    fn test_negative_positions_survive_round_trip() {
+        let mut state = PongState::new();
+        state.ball = (-12, -25);
+        state.ball_vel = (-15, -8);
+
+        let msg = ServerMsg::State(state, InputAck::default());
+        assert_eq!(decode_server_msg(&encode_server_msg(&msg)), Ok(msg));
+    }
+
+    #[test]
+    fn test_encoded_msgs_are_smaller_than_bincode() {
+        for msg in server_msgs() {
+            let packed = encode_server_msg(&msg).len();
+            let bincoded = bincode::serialize(&msg).unwrap().len();
+            assert!(packed <= bincoded, "{:?}: {} vs {}", msg, packed, bincoded);
+        }
+
+        for msg in client_msgs() {
+            let packed = encode_client_msg(&msg).len();
+            let bincoded = bincode::serialize(&msg).unwrap().len();
+            assert!(packed <= bincoded, "{:?}: {} vs {}", msg, packed, bincoded);
+        }
+
+        // The one sent the most should be a lot smaller.
+        let state = ServerMsg::State(played_state(), InputAck { seq: 17, ticks: 300 });
+        let packed = encode_server_msg(&state).len();
+        let bincoded = bincode::serialize(&state).unwrap().len();
+        assert!(packed * 2 < bincoded, "{} vs {}", packed, bincoded);
+    }
+
+    #[test]
+    fn test_wrong_version_is_rejected() {
+        let mut bytes = encode_client_msg(&ClientMsg::AckState(5));
+        bytes[0] = PROTOCOL_VERSION + 1;
+
+        assert_eq!(decode_client_msg(&bytes), Err(DecodeError::WrongVersion(PROTOCOL_VERSION + 1)));
+    }
+
+    #[test]
+    fn test_handshake_is_decoded_from_any_version() {
+        let hello = Hello { version: PROTOCOL_VERSION + 1, ..Hello::with_name("Dave") };
+        let mut bytes = encode_client_msg(&ClientMsg::Hi(hello.clone()));
+        bytes[0] = PROTOCOL_VERSION + 1;
+        assert_eq!(decode_client_msg(&bytes), Ok(ClientMsg::Hi(hello)));
+
+        // Versions from before names end right after the capabilities.
+        let mut w = BitWriter::new();
+        w.write(NAMES_SINCE_VERSION as u64 - 1, 8);
+        w.write(CLIENT_HI_TAG as u64, TAG_BITS);
+        w.write(NAMES_SINCE_VERSION as u64 - 1, 8);
+        w.write_varint(1);
+        let hello = Hello { version: NAMES_SINCE_VERSION - 1, ..Hello::new() };
+        assert_eq!(decode_client_msg(&w.finish()), Ok(ClientMsg::Hi(hello)));
+
+        let reject = ServerMsg::Reject(RejectReason::UnsupportedVersion { server_version: 0 });
+        let mut bytes = encode_server_msg(&reject);
+        bytes[0] = 0;
+        assert_eq!(decode_server_msg(&bytes), Ok(reject));
+    }
+
+    #[test]
+    fn test_unknown_capabilities_are_ignored() {
+        let mut w = BitWriter::new();
+        w.write(PROTOCOL_VERSION as u64, 8);
+        w.write(SERVER_ACCEPT_TAG as u64, TAG_BITS);
+        w.write_varint(0b111);
+
+        let msg = decode_server_msg(&w.finish());
+        assert_eq!(msg, Ok(ServerMsg::Accept(Capabilities::all())));
+    }
+
+    #[test]
+    fn test_truncated_msgs_are_rejected() {
+        // A full state.
+        let bytes = encode_server_msg(&server_msgs()[4]);
+
+        for len in 0..bytes.len() {
+            assert!(decode_server_msg(&bytes[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_moves_without_movement_are_invalid() {
+        let mut w = BitWriter::new();
+        w.write(PROTOCOL_VERSION as u64, 8);
+        w.write(5, TAG_BITS);
+        w.write_varint(1);
+        write_movement(&mut w, None);
+        w.write_varint(0);
+
+        assert_eq!(decode_client_msg(&w.finish()), Err(DecodeError::InvalidValue));
+    }
+
+    #[test]
+    fn test_numbers_too_big_for_their_field_overflow() {
+        let mut w = BitWriter::new();
+        w.write(PROTOCOL_VERSION as u64, 8);
+        w.write(6, TAG_BITS);
+        w.write_varint(u32::MAX as u64 + 1);
+        assert_eq!(decode_client_msg(&w.finish()), Err(DecodeError::Overflow));
+
+        // More groups than fit in a u64.
+        let mut bytes = vec![PROTOCOL_VERSION, 0x1f];
+        bytes.extend_from_slice(&[0xff; 12]);
+        assert_eq!(decode_client_msg(&bytes), Err(DecodeError::Overflow));
+
+        let mut w = BitWriter::new();
+        w.write_varint(u64::MAX);
+        assert_eq!(BitReader::new(&w.finish()).read_varint(), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn test_unknown_tags_are_rejected() {
+        let bytes = [PROTOCOL_VERSION, 0xf0];
+        assert_eq!(decode_server_msg(&bytes), Err(DecodeError::UnknownTag(15)));
+        assert_eq!(decode_client_msg(&bytes), Err(DecodeError::UnknownTag(15)));
+    }
+
+    #[test]
+    fn test_transport_events_are_not_accepted_from_the_wire() {
+        for tag in 2..=4 {
+            let bytes = [PROTOCOL_VERSION, tag << 4];
+            assert_eq!(decode_client_msg(&bytes), Err(DecodeError::UnknownTag(tag)));
+        }
+    }
+}
@@ -0,0 +1,358 @@
+// Delta compressed states.
+//
+// Instead of sending the whole PongState every time, the server sends only
+// the fields that changed since a state the client has acknowledged (the
+// baseline). Most of the time only the ball and the tick change, which is a
+// lot less to send. Every now and then, or when there is no acknowledged
+// baseline, a full state (a keyframe) is sent instead.
+
+use crate::game_constants::BROADCASTS_PER_KEYFRAME;
+use crate::net_messages::PlayerOrder;
+use crate::pong_state::{PongState, PlayerMovement};
+use crate::rng::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+// How many sent or received states are kept around as possible baselines.
+const MAX_HISTORY: usize = 64;
+
+// The fields of a PongState that differ from the baseline. None means that the
+// field is the same as in the baseline.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+pub struct StateDelta {
+    // The tick of the baseline this delta should be applied to.
+    pub base_tick: u32,
+
+    // Always changes, so it's always sent.
+    pub ticks: u32,
+
+    pub p1: Option<i32>,
+    pub p1_move: Option<Option<PlayerMovement>>,
+    pub p2: Option<i32>,
+    pub p2_move: Option<Option<PlayerMovement>>,
+    pub ball: Option<(i32, i32)>,
+    pub ball_vel: Option<(i32, i32)>,
+    pub p1_score: Option<u32>,
+    pub p2_score: Option<u32>,
+    pub points_to_win: Option<u32>,
+    pub serving: Option<PlayerOrder>,
+    pub serve_delay: Option<u32>,
+    pub rng: Option<Rng>,
+}
+
+fn changed<T: PartialEq + Copy>(old: T, new: T) -> Option<T> {
+    if old == new {
+        None
+    } else {
+        Some(new)
+    }
+}
+
+impl StateDelta {
+    pub fn between(base: &PongState, state: &PongState) -> Self {
+        Self {
+            base_tick: base.ticks,
+            ticks: state.ticks,
+            p1: changed(base.p1, state.p1),
+            p1_move: changed(base.p1_move, state.p1_move),
+            p2: changed(base.p2, state.p2),
+            p2_move: changed(base.p2_move, state.p2_move),
+            ball: changed(base.ball, state.ball),
+            ball_vel: changed(base.ball_vel, state.ball_vel),
+            p1_score: changed(base.p1_score, state.p1_score),
+            p2_score: changed(base.p2_score, state.p2_score),
+            points_to_win: changed(base.points_to_win, state.points_to_win),
+            serving: changed(base.serving, state.serving),
+            serve_delay: changed(base.serve_delay, state.serve_delay),
+            rng: changed(base.rng, state.rng),
+        }
+    }
+
+    pub fn apply(&self, base: &PongState) -> PongState {
+        PongState {
+            ticks: self.ticks,
+            p1: self.p1.unwrap_or(base.p1),
+            p1_move: self.p1_move.unwrap_or(base.p1_move),
+            p2: self.p2.unwrap_or(base.p2),
+            p2_move: self.p2_move.unwrap_or(base.p2_move),
+            ball: self.ball.unwrap_or(base.ball),
+            ball_vel: self.ball_vel.unwrap_or(base.ball_vel),
+            p1_score: self.p1_score.unwrap_or(base.p1_score),
+            p2_score: self.p2_score.unwrap_or(base.p2_score),
+            points_to_win: self.points_to_win.unwrap_or(base.points_to_win),
+            serving: self.serving.unwrap_or(base.serving),
+            serve_delay: self.serve_delay.unwrap_or(base.serve_delay),
+            rng: self.rng.unwrap_or(base.rng),
+        }
+    }
+}
+
+// A state on its way to a client, either whole or as a delta.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Snapshot {
+    Full(PongState),
+    Delta(StateDelta),
+}
+
+// Recent states, oldest first, looked up by their tick.
+#[derive(Debug, Default, Clone)]
+struct StateHistory {
+    states: VecDeque<PongState>,
+}
+
+impl StateHistory {
+    // States that aren't newer than the newest one arrived late or twice,
+    // and are dropped.
+    fn push(&mut self, state: PongState) {
+        if self.is_stale(state.ticks) {
+            return;
+        }
+
+        if self.states.len() >= MAX_HISTORY {
+            self.states.pop_front();
+        }
+        self.states.push_back(state);
+    }
+
+    fn is_stale(&self, tick: u32) -> bool {
+        matches!(self.states.back(), Some(newest) if tick <= newest.ticks)
+    }
+
+    fn find(&self, tick: u32) -> Option<PongState> {
+        self.states.iter().find(|state| state.ticks == tick).copied()
+    }
+
+    fn forget_before(&mut self, tick: u32) {
+        self.states.retain(|state| state.ticks >= tick);
+    }
+}
+
+// Server side, one per client.
+#[derive(Debug, Default, Clone)]
+pub struct DeltaEncoder {
+    // States sent to the client that it hasn't acknowledged yet.
+    sent: StateHistory,
+
+    // The latest state the client has acknowledged.
+    baseline: Option<PongState>,
+
+    broadcasts_since_keyframe: u32,
+}
+
+impl DeltaEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn encode(&mut self, state: PongState) -> Snapshot {
+        self.sent.push(state);
+
+        match self.baseline {
+            Some(base) if self.broadcasts_since_keyframe < BROADCASTS_PER_KEYFRAME => {
+                self.broadcasts_since_keyframe += 1;
+                Snapshot::Delta(StateDelta::between(&base, &state))
+            }
+
+            _ => {
+                self.broadcasts_since_keyframe = 0;
+                Snapshot::Full(state)
+            }
+        }
+    }
+
+    // The client got the state with this tick, so it can be used as baseline.
+    pub fn ack(&mut self, tick: u32) {
+        // Acks can arrive late or out of order.
+        if let Some(base) = self.baseline {
+            if tick <= base.ticks {
+                return;
+            }
+        }
+
+        if let Some(state) = self.sent.find(tick) {
+            self.baseline = Some(state);
+            self.sent.forget_before(tick);
+        }
+    }
+}
+
+// Client side, turns snapshots back into whole states.
+#[derive(Debug, Default)]
+pub struct DeltaDecoder {
+    received: StateHistory,
+}
+
+impl DeltaDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns None for a delta whose baseline we don't have (anymore), a
+    // keyframe will come along soon enough. Also None for states older than
+    // the newest one, so that we never go back in time.
+    pub fn decode(&mut self, snapshot: Snapshot) -> Option<PongState> {
+        let ticks = match snapshot {
+            Snapshot::Full(state) => state.ticks,
+            Snapshot::Delta(delta) => delta.ticks,
+        };
+        if matches!(self.newest_tick(), Some(newest) if ticks < newest) {
+            return None;
+        }
+
+        let state = match snapshot {
+            Snapshot::Full(state) => state,
+            Snapshot::Delta(delta) => delta.apply(&self.received.find(delta.base_tick)?),
+        };
+
+        self.received.push(state);
+        Some(state)
+    }
+
+    pub fn newest_tick(&self) -> Option<u32> {
+        self.received.states.back().map(|state| state.ticks)
+    }
+
+    // Ticks start over when a new match starts, the old states are no use.
+    pub fn reset(&mut self) {
+        self.received.states.clear();
+    }
+}
+
+#[cfg(test)]
+mod delta_tests {
+    use super::*;
+
+    fn ticked(mut state: PongState, ticks: u32) -> PongState {
+        for _ in 0..ticks {
+            state.tick(Some(PlayerMovement::Down), None);
+        }
+        state
+    }
+
+    #[test]
+    fn test_delta_applied_to_base_gives_state() {
+        let base = PongState::new();
+        let mut state = ticked(base, 10);
+        state.p2_score = 3;
+
+        let delta = StateDelta::between(&base, &state);
+        assert_eq!(delta.apply(&base), state);
+    }
+
+    #[test]
+    fn test_delta_only_contains_changes() {
+        let base = PongState::new();
+        let state = ticked(base, 1);
+
+        let delta = StateDelta::between(&base, &state);
+        assert!(delta.ball.is_some());
+        assert!(delta.p1.is_some());
+        assert_eq!(delta.p2, None);
+        assert_eq!(delta.p1_score, None);
+        assert_eq!(delta.rng, None);
+    }
+
+    #[test]
+    fn test_delta_is_smaller_than_full_state() {
+        let base = PongState::new();
+        let mut state = base;
+        state.tick(None, None);
+
+        let full = bincode::serialize(&state).unwrap().len();
+        let delta = bincode::serialize(&StateDelta::between(&base, &state)).unwrap().len();
+        assert!(delta * 2 < full, "delta: {}, full: {}", delta, full);
+    }
+
+    #[test]
+    fn test_encoder_sends_full_states_until_acked() {
+        let mut encoder = DeltaEncoder::new();
+        let first = ticked(PongState::new(), 1);
+        let second = ticked(first, 1);
+
+        assert_eq!(encoder.encode(first), Snapshot::Full(first));
+        assert_eq!(encoder.encode(second), Snapshot::Full(second));
+
+        encoder.ack(first.ticks);
+        let third = ticked(second, 1);
+        assert_eq!(
+            encoder.encode(third),
+            Snapshot::Delta(StateDelta::between(&first, &third))
+        );
+    }
+
+    #[test]
+    fn test_encoder_sends_keyframes_regularly() {
+        let mut encoder = DeltaEncoder::new();
+        let mut state = ticked(PongState::new(), 1);
+
+        encoder.encode(state);
+        encoder.ack(state.ticks);
+
+        let mut fulls = 0;
+        for _ in 0..(BROADCASTS_PER_KEYFRAME * 2 + 2) {
+            state = ticked(state, 1);
+            if let Snapshot::Full(_) = encoder.encode(state) {
+                fulls += 1;
+            }
+        }
+        assert_eq!(fulls, 2);
+    }
+
+    #[test]
+    fn test_decoder_rebuilds_states() {
+        let mut encoder = DeltaEncoder::new();
+        let mut decoder = DeltaDecoder::new();
+        let mut state = PongState::new();
+
+        for _ in 0..50 {
+            state = ticked(state, 3);
+            let decoded = decoder.decode(encoder.encode(state)).unwrap();
+            assert_eq!(decoded, state);
+            encoder.ack(decoded.ticks);
+        }
+    }
+
+    #[test]
+    fn test_decoder_forgets_states_from_previous_match() {
+        let mut decoder = DeltaDecoder::new();
+        let old_match = ticked(PongState::new(), 5);
+        decoder.decode(Snapshot::Full(old_match));
+        decoder.reset();
+
+        let base = ticked(PongState::new(), 2);
+        decoder.decode(Snapshot::Full(base));
+
+        // Same tick as a state from the old match.
+        let state = ticked(base, 3);
+        let delta = StateDelta::between(&old_match, &state);
+        assert_eq!(decoder.decode(Snapshot::Delta(delta)), None);
+    }
+
+    #[test]
+    fn test_decoder_drops_late_states_but_keeps_its_baselines() {
+        let mut decoder = DeltaDecoder::new();
+        let base = ticked(PongState::new(), 2);
+        let newest = ticked(base, 3);
+        decoder.decode(Snapshot::Full(base));
+        decoder.decode(Snapshot::Full(newest));
+
+        // Arrived out of order.
+        let late = ticked(base, 1);
+        assert_eq!(decoder.decode(Snapshot::Full(late)), None);
+        assert_eq!(decoder.newest_tick(), Some(newest.ticks));
+
+        let state = ticked(newest, 2);
+        let delta = StateDelta::between(&base, &state);
+        assert_eq!(decoder.decode(Snapshot::Delta(delta)), Some(state));
+    }
+
+    #[test]
+    fn test_decoder_skips_deltas_without_baseline() {
+        let mut decoder = DeltaDecoder::new();
+        let base = PongState::new();
+        let state = ticked(base, 1);
+
+        let delta = Snapshot::Delta(StateDelta::between(&base, &state));
+        assert_eq!(decoder.decode(delta), None);
+    }
+}
@@ -0,0 +1,76 @@
+// Everything that can go wrong for the server or a client, in terms of the
+// game rather than the network library underneath.
+
+use crate::codec::DecodeError;
+use crate::net_messages::RejectReason;
+use std::fmt;
+use std::net::SocketAddr;
+
+#[derive(Debug)]
+pub enum Error {
+    // Not something that can be parsed as an ip and a port.
+    InvalidAddress(String),
+
+    // Couldn't get a socket for the address.
+    Bind(String, String),
+
+    // A packet couldn't be handed to the network.
+    Send(String),
+
+    // A packet that isn't one of our messages.
+    Decode(DecodeError),
+
+    // The server won't play with us.
+    Protocol(RejectReason),
+
+    // Nothing has been heard from this address in too long.
+    Timeout(SocketAddr),
+
+    // The window or drawing failed.
+    Graphics(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidAddress(addr) => write!(f, "{} is not a valid address, expected ip:port", addr),
+            Error::Bind(addr, reason) => write!(f, "Could not bind to {}: {}", addr, reason),
+            Error::Send(reason) => write!(f, "Could not send: {}", reason),
+            Error::Decode(err) => write!(f, "Could not make sense of a packet: {:?}", err),
+            Error::Protocol(reason) => write!(f, "{}", reason),
+            Error::Timeout(addr) => write!(f, "Lost contact with {}", addr),
+            Error::Graphics(reason) => write!(f, "Graphics failed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<DecodeError> for Error {
+    fn from(err: DecodeError) -> Self {
+        Error::Decode(err)
+    }
+}
+
+pub fn parse_addr(addr: &str) -> Result<SocketAddr> {
+    addr.parse().map_err(|_| Error::InvalidAddress(addr.to_string()))
+}
+
+#[cfg(test)]
+mod error_tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_addresses_are_errors() {
+        assert!(parse_addr("127.0.0.1:6666").is_ok());
+        assert!(matches!(parse_addr("localhost"), Err(Error::InvalidAddress(_))));
+    }
+
+    #[test]
+    fn test_rejections_explain_themselves() {
+        let err = Error::Protocol(RejectReason::UnsupportedVersion { server_version: 1 });
+        assert!(err.to_string().contains("version 1"));
+    }
+}
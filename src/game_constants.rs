@@ -9,8 +9,26 @@ pub const HALF_PADDLE_WIDTH: i32 = PADDLE_WIDTH/2;
 pub const BALL_WIDTH: i32 = 25;
 pub const BALL_HEIGHT: i32 = 25;
 
+// The ball starts every rally at this speed and gets BALL_SPEED_UP faster
+// horizontally for each paddle hit, up to BALL_MAX_SPEED.
+pub const BALL_START_VEL: (i32, i32) = (5, 7);
+pub const BALL_SPEED_UP: i32 = 1;
+pub const BALL_MAX_SPEED: i32 = 15;
+
+// How many ticks a served ball waits before it starts moving.
+pub const SERVE_DELAY_TICKS: u32 = 60;
+
+// Vertical speed of a ball hitting the very edge of a paddle. Hits closer to
+// the center give flatter bounces.
+pub const BALL_MAX_VERTICAL_SPEED: i32 = 8;
+
 pub const PLAYER_MOVE_UNIT: i32 = 5;
 
+// A match is won by the first player to reach POINTS_TO_WIN points, as long as
+// they are at least WIN_BY points ahead of the opponent.
+pub const POINTS_TO_WIN: u32 = 11;
+pub const WIN_BY: u32 = 2;
+
 // Horizontal positions
 pub const P1_X_POS: i32 = 0;
 pub const P2_X_POS: i32 = GAME_WIDTH - PADDLE_WIDTH;
@@ -19,5 +37,55 @@ pub const P2_X_POS: i32 = GAME_WIDTH - PADDLE_WIDTH;
 pub const CLIENT_UPDATES_PER_SECONDS: f64 = 60.0;
 pub const MS_PER_UPDATE: u64 = (1000.0 / CLIENT_UPDATES_PER_SECONDS) as u64;
 
+// How many of its own ticks a client remembers while waiting for the server to
+// acknowledge them, about two seconds worth.
+pub const MAX_PREDICTED_TICKS: usize = 120;
+
+// Clients render the opponent and the ball this far in the past, so that they
+// can interpolate between two states from the server. When states are late,
+// they extrapolate at most this many ticks.
+pub const INTERPOLATION_DELAY_MS: u64 = 100;
+pub const MAX_EXTRAPOLATED_TICKS: u64 = 30;
+
+// Movement changes are applied from the tick the client says they're for, but
+// at most this many ticks after they arrive, however far ahead the client is.
+pub const MAX_INPUT_LEAD_TICKS: u32 = 30;
+
 pub const MS_PER_PING: u64 = 500;
 pub const MS_PER_STATE_BROADCAST: u64 = 50;
+
+// Spectators don't need to be as up to date as the players.
+pub const MS_PER_SPECTATOR_BROADCAST: u64 = 100;
+
+// When pairing by ping, players wait at most this long for their round trip
+// time to be measured before they're paired anyway.
+pub const PING_PAIRING_WAIT_MS: u64 = 2000;
+
+// How long a spectator waits before asking again when there's nothing to watch.
+pub const SPECTATE_RETRY_MS: u64 = 1000;
+
+// Most state broadcasts only contain what changed since the latest state a
+// client acknowledged. Every this many broadcasts, a full state is sent.
+pub const BROADCASTS_PER_KEYFRAME: u32 = 20;
+
+// Anything bigger than this from a client isn't one of our messages, the
+// largest ones are a few bytes.
+pub const MAX_CLIENT_PAYLOAD_BYTES: usize = 64;
+
+// A client sends at most a movement change per frame, an ack per state and a
+// pong per ping. More than this per second from one address is dropped.
+pub const MAX_CLIENT_PACKETS_PER_SECOND: u32 = 120;
+
+// How long a match waits for a player that lost contact to come back before
+// it's aborted.
+pub const RECONNECT_GRACE_MS: u64 = 10_000;
+
+// A player can be resumed from another address while the match is running
+// once we haven't heard from it for this long. Players send an ack for every
+// state, so this is a lot of missed packets.
+pub const RESUME_AFTER_SILENCE_MS: u64 = 2000;
+
+// A client that lost contact with the server tries to get back in right away,
+// then waits twice as long after every attempt, up to the max.
+pub const RECONNECT_MIN_BACKOFF_MS: u64 = 250;
+pub const RECONNECT_MAX_BACKOFF_MS: u64 = 4000;
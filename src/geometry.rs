@@ -0,0 +1,141 @@
+// Small integer geometry helpers for the game simulation. Keeping these in the
+// crate means the simulation doesn't need any graphics library.
+
+use std::cmp::{max, Ordering};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Vec2 {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl Vec2 {
+    pub fn new(x: i32, y: i32) -> Self {
+        Self { x, y }
+    }
+
+    // Where a point moving with `vel` is after the part `time` of the move.
+    pub fn moved(self, vel: Vec2, time: MoveTime) -> Self {
+        Self {
+            x: self.x + vel.x * time.num / time.den,
+            y: self.y + vel.y * time.num / time.den,
+        }
+    }
+}
+
+impl From<(i32, i32)> for Vec2 {
+    fn from((x, y): (i32, i32)) -> Self {
+        Self { x, y }
+    }
+}
+
+impl From<Vec2> for (i32, i32) {
+    fn from(v: Vec2) -> Self {
+        (v.x, v.y)
+    }
+}
+
+// An axis aligned rectangle, (x, y) being the top left corner.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub w: i32,
+    pub h: i32,
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, w: i32, h: i32) -> Self {
+        Self { x, y, w, h }
+    }
+
+    pub fn left(&self) -> i32 {
+        self.x
+    }
+
+    pub fn right(&self) -> i32 {
+        self.x + self.w
+    }
+
+    pub fn top(&self) -> i32 {
+        self.y
+    }
+
+    pub fn bottom(&self) -> i32 {
+        self.y + self.h
+    }
+
+    // Touching edges count as overlapping.
+    pub fn overlaps(&self, other: &Rect) -> bool {
+        self.left() <= other.right() &&
+            self.right() >= other.left() &&
+            self.top() <= other.bottom() &&
+            self.bottom() >= other.top()
+    }
+}
+
+// A point in time during a move, as the fraction num/den of the whole move.
+// Kept as integers so every machine computes exactly the same thing.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MoveTime {
+    pub num: i32,
+    pub den: i32,
+}
+
+impl MoveTime {
+    // Compares two fractions without dividing.
+    pub fn compare(&self, other: &MoveTime) -> Ordering {
+        (self.num * other.den).cmp(&(other.num * self.den))
+    }
+}
+
+// When during a move of `vel` does an edge at `pos` reach `line`? An edge
+// already past the line (in the direction it moves) reaches it right away.
+// Returns None if the edge doesn't get all the way there.
+pub fn time_to_reach(pos: i32, vel: i32, line: i32) -> Option<MoveTime> {
+    if vel < 0 && pos + vel <= line {
+        Some(MoveTime { num: max(0, pos - line), den: -vel })
+    } else if vel > 0 && pos + vel >= line {
+        Some(MoveTime { num: max(0, line - pos), den: vel })
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod geometry_tests {
+    use super::*;
+
+    #[test]
+    fn test_rects_overlap_when_touching() {
+        let a = Rect::new(0, 0, 10, 10);
+
+        assert!(a.overlaps(&Rect::new(5, 5, 10, 10)));
+        assert!(a.overlaps(&Rect::new(10, 0, 10, 10)));
+        assert!(!a.overlaps(&Rect::new(11, 0, 10, 10)));
+        assert!(!a.overlaps(&Rect::new(0, -20, 10, 10)));
+    }
+
+    #[test]
+    fn test_time_to_reach_line() {
+        assert_eq!(time_to_reach(10, -20, 5), Some(MoveTime { num: 5, den: 20 }));
+        assert_eq!(time_to_reach(0, 20, 5), Some(MoveTime { num: 5, den: 20 }));
+
+        // Too slow, or not moving at all
+        assert_eq!(time_to_reach(10, -2, 5), None);
+        assert_eq!(time_to_reach(10, 0, 5), None);
+
+        // Already past the line
+        assert_eq!(time_to_reach(3, -20, 5), Some(MoveTime { num: 0, den: 20 }));
+        assert_eq!(time_to_reach(10, 20, 5), Some(MoveTime { num: 0, den: 20 }));
+    }
+
+    #[test]
+    fn test_moved_point() {
+        let start = Vec2::new(10, 10);
+        let vel = Vec2::new(-20, 8);
+
+        assert_eq!(start.moved(vel, MoveTime { num: 1, den: 2 }), Vec2::new(0, 14));
+        assert_eq!(start.moved(vel, MoveTime { num: 1, den: 1 }), Vec2::new(-10, 18));
+    }
+}
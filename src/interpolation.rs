@@ -0,0 +1,184 @@
+// Smooth rendering of what the server controls, i e the opponent paddle and
+// the ball.
+//
+// States from the server are kept together with when they arrived. The client
+// renders a bit in the past, `delay` behind now, where it usually has a state
+// on both sides to interpolate between. If the states stop coming, it falls
+// back to extrapolating from the newest one.
+
+use crate::game_constants::{MS_PER_UPDATE, MAX_EXTRAPOLATED_TICKS};
+use crate::pong_state::PongState;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// More than enough to cover any sensible delay.
+const MAX_SNAPSHOTS: usize = 32;
+
+#[derive(Debug)]
+pub struct SnapshotBuffer {
+    // How far in the past we render.
+    delay: Duration,
+
+    // States from the server and when they arrived, oldest first.
+    snapshots: VecDeque<(Instant, PongState)>,
+}
+
+impl SnapshotBuffer {
+    pub fn new(delay: Duration) -> Self {
+        Self { delay, snapshots: VecDeque::new() }
+    }
+
+    pub fn push(&mut self, received: Instant, state: PongState) {
+        self.snapshots.push_back((received, state));
+
+        // Only one state older than the render time is needed.
+        let render_time = received.checked_sub(self.delay);
+        while self.snapshots.len() > MAX_SNAPSHOTS || self.second_oldest_before(render_time) {
+            self.snapshots.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+
+    // What the state looked like `delay` ago.
+    pub fn sample(&self, now: Instant) -> Option<PongState> {
+        let (newest_time, newest) = *self.snapshots.back()?;
+        let (oldest_time, oldest) = *self.snapshots.front()?;
+
+        let render_time = match now.checked_sub(self.delay) {
+            Some(time) if time >= oldest_time => time,
+            _ => return Some(oldest),
+        };
+
+        if render_time >= newest_time {
+            return Some(extrapolate(newest, render_time - newest_time));
+        }
+
+        let pairs = self.snapshots.iter().zip(self.snapshots.iter().skip(1));
+        for (&(from_time, from), &(to_time, to)) in pairs {
+            if from_time <= render_time && render_time < to_time {
+                let done = (render_time - from_time).as_micros() as i64;
+                let total = (to_time - from_time).as_micros() as i64;
+                return Some(interpolate(from, to, done, total));
+            }
+        }
+
+        Some(newest)
+    }
+
+    fn second_oldest_before(&self, time: Option<Instant>) -> bool {
+        match (self.snapshots.get(1), time) {
+            (Some((received, _)), Some(time)) => *received <= time,
+            _ => false,
+        }
+    }
+}
+
+// Moves positions from `from` towards `to`, done/total of the way.
+fn interpolate(from: PongState, to: PongState, done: i64, total: i64) -> PongState {
+    // The ball jumps back to the middle after a point, don't draw it flying
+    // across the field.
+    if from.p1_score != to.p1_score || from.p2_score != to.p2_score {
+        return to;
+    }
+
+    let lerp = |a: i32, b: i32| (a as i64 + (b as i64 - a as i64) * done / total) as i32;
+
+    let mut state = from;
+    state.p1 = lerp(from.p1, to.p1);
+    state.p2 = lerp(from.p2, to.p2);
+    state.ball = (lerp(from.ball.0, to.ball.0), lerp(from.ball.1, to.ball.1));
+    state
+}
+
+// Guesses how the state went on after it was sent, assuming both players kept
+// moving the same way.
+fn extrapolate(mut state: PongState, elapsed: Duration) -> PongState {
+    let ticks = (elapsed.as_millis() as u64 / MS_PER_UPDATE).min(MAX_EXTRAPOLATED_TICKS);
+    for _ in 0..ticks {
+        state.tick(state.p1_move, state.p2_move);
+    }
+    state
+}
+
+#[cfg(test)]
+mod interpolation_tests {
+    use super::*;
+
+    fn state_with_ball(x: i32) -> PongState {
+        let mut state = PongState::new();
+        state.ball = (x, 100);
+        state
+    }
+
+    #[test]
+    fn test_sample_interpolates_between_snapshots() {
+        let start = Instant::now();
+        let delay = Duration::from_millis(100);
+        let mut buffer = SnapshotBuffer::new(delay);
+
+        buffer.push(start, state_with_ball(100));
+        buffer.push(start + Duration::from_millis(50), state_with_ball(200));
+
+        let state = buffer.sample(start + delay + Duration::from_millis(25)).unwrap();
+        assert_eq!(state.ball, (150, 100));
+    }
+
+    #[test]
+    fn test_sample_extrapolates_when_snapshots_are_late() {
+        let start = Instant::now();
+        let delay = Duration::from_millis(100);
+        let mut buffer = SnapshotBuffer::new(delay);
+
+        let newest = state_with_ball(100);
+        buffer.push(start, newest);
+
+        let late = Duration::from_millis(MS_PER_UPDATE * 3);
+        let state = buffer.sample(start + delay + late).unwrap();
+
+        let mut expected = newest;
+        for _ in 0..3 {
+            expected.tick(None, None);
+        }
+        assert_eq!(state, expected);
+    }
+
+    #[test]
+    fn test_sample_does_not_interpolate_across_a_point() {
+        let start = Instant::now();
+        let delay = Duration::from_millis(100);
+        let mut buffer = SnapshotBuffer::new(delay);
+
+        let before = state_with_ball(600);
+        let mut after = state_with_ball(320);
+        after.p1_score = 1;
+
+        buffer.push(start, before);
+        buffer.push(start + Duration::from_millis(50), after);
+
+        let state = buffer.sample(start + delay + Duration::from_millis(25)).unwrap();
+        assert_eq!(state, after);
+    }
+
+    #[test]
+    fn test_old_snapshots_are_dropped() {
+        let start = Instant::now();
+        let mut buffer = SnapshotBuffer::new(Duration::from_millis(100));
+
+        for i in 0..10 {
+            buffer.push(start + Duration::from_millis(i * 50), state_with_ball(0));
+        }
+
+        // The render time is now at 350ms, older ones aren't needed.
+        assert_eq!(buffer.snapshots.len(), 3);
+        assert_eq!(buffer.snapshots[0].0, start + Duration::from_millis(350));
+    }
+
+    #[test]
+    fn test_sample_without_snapshots_is_none() {
+        let buffer = SnapshotBuffer::new(Duration::from_millis(100));
+        assert_eq!(buffer.sample(Instant::now()), None);
+    }
+}
@@ -0,0 +1,32 @@
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "client")]
+pub mod client_connection;
+pub mod backoff;
+pub mod channels;
+pub mod clock;
+pub mod codec;
+pub mod delta;
+pub mod error;
+pub mod game_constants;
+pub mod geometry;
+pub mod interpolation;
+pub mod link_conditioner;
+pub mod net_messages;
+pub mod pong_state;
+pub mod prediction;
+pub mod rng;
+pub mod transport;
+#[cfg(feature = "server")]
+pub mod matchmaking;
+#[cfg(feature = "server")]
+pub mod packet_filter;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "server")]
+pub mod server_network;
+#[cfg(feature = "server")]
+pub mod server_state;
+
+#[cfg(all(test, feature = "server"))]
+mod test_helper;
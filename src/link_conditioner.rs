@@ -0,0 +1,397 @@
+// Makes a good network look bad, for trying out the game under latency,
+// jitter, loss, duplication and reordering on a single machine. Wraps any
+// transport and holds back, drops or repeats packets on their way in and out.
+// Everything random comes from a seed, so a bad run can be replayed.
+//
+// This sits above laminar, so a packet dropped here is gone for good, even on
+// a reliable channel. On a real network laminar would resend it, so control
+// packets are only ever delayed, by the same amount each, which keeps them in
+// order too.
+
+use crate::channels::Channel;
+use crate::rng::Rng;
+use crate::transport::{Transport, TransportEvent};
+use crate::error::Result;
+use clap::Clap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// How much longer a reordered packet is held back than it would have been.
+const REORDER_DELAY_MS: u64 = 50;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct LinkConditions {
+    // Added to every packet, each way.
+    pub delay_ms: u64,
+
+    // Every packet gets up to this much more or less delay, evenly spread.
+    pub jitter_ms: u64,
+
+    // Chances in percent of a packet being lost, arriving twice or being held
+    // back long enough to arrive after later packets.
+    pub loss_percent: u32,
+    pub duplicate_percent: u32,
+    pub reorder_percent: u32,
+
+    pub seed: u64,
+}
+
+impl LinkConditions {
+    pub fn is_perfect(&self) -> bool {
+        let perfect = Self { seed: self.seed, ..Self::default() };
+        *self == perfect
+    }
+
+    // When copies of a packet sent now should arrive: none if it's lost, two
+    // if it's duplicated.
+    fn schedule(&self, rng: &mut Rng, now: Instant, channel: Channel) -> Vec<Instant> {
+        if channel == Channel::Control {
+            return vec![now + Duration::from_millis(self.delay_ms)];
+        }
+
+        if chance(rng, self.loss_percent) {
+            return vec![];
+        }
+
+        let copies = if chance(rng, self.duplicate_percent) { 2 } else { 1 };
+        (0..copies).map(|_| now + self.delay(rng)).collect()
+    }
+
+    fn delay(&self, rng: &mut Rng) -> Duration {
+        let jitter = self.jitter_ms as i64;
+        let mut delay_ms = self.delay_ms as i64 + rng.range(-jitter as i32, jitter as i32) as i64;
+
+        if chance(rng, self.reorder_percent) {
+            delay_ms += REORDER_DELAY_MS as i64;
+        }
+        Duration::from_millis(delay_ms.max(0) as u64)
+    }
+}
+
+// The flags both the server and the client take for pretending to be on a bad
+// network.
+#[derive(Clap, Debug)]
+pub struct LinkOpts {
+    /// Simulated delay in ms added to every packet, each way
+    #[clap(long, default_value = "0")]
+    delay: u64,
+
+    /// Simulated jitter in ms, every packet gets up to this much more or less delay
+    #[clap(long, default_value = "0")]
+    jitter: u64,
+
+    /// Simulated packet loss in percent
+    #[clap(long, default_value = "0", parse(try_from_str = percent))]
+    loss: u32,
+
+    /// Simulated packet duplication in percent
+    #[clap(long, default_value = "0", parse(try_from_str = percent))]
+    duplicate: u32,
+
+    /// Simulated packet reordering in percent
+    #[clap(long, default_value = "0", parse(try_from_str = percent))]
+    reorder: u32,
+
+    /// Seed for the simulated network conditions
+    #[clap(long, default_value = "0")]
+    seed: u64,
+}
+
+impl LinkOpts {
+    pub fn link_conditions(&self) -> LinkConditions {
+        LinkConditions {
+            delay_ms: self.delay,
+            jitter_ms: self.jitter,
+            loss_percent: self.loss,
+            duplicate_percent: self.duplicate,
+            reorder_percent: self.reorder,
+            seed: self.seed,
+        }
+    }
+}
+
+fn percent(value: &str) -> std::result::Result<u32, String> {
+    match value.parse::<u32>() {
+        Ok(percent) if percent <= 100 => Ok(percent),
+        _ => Err(format!("{} is not a percentage between 0 and 100", value)),
+    }
+}
+
+fn chance(rng: &mut Rng, percent: u32) -> bool {
+    percent > 0 && (rng.range(0, 99) as u32) < percent
+}
+
+// Packets waiting for their time to arrive. Ones due at the same time keep
+// the order they came in.
+struct Delayed<T> {
+    queue: Vec<(Instant, u64, T)>,
+    next_order: u64,
+}
+
+impl<T> Delayed<T> {
+    fn new() -> Self {
+        Self { queue: vec![], next_order: 0 }
+    }
+
+    fn push(&mut self, due: Instant, item: T) {
+        self.queue.push((due, self.next_order, item));
+        self.next_order += 1;
+    }
+
+    fn pop_due(&mut self, now: Instant) -> Option<T> {
+        let (index, _) = self.queue
+            .iter()
+            .enumerate()
+            .filter(|(_, (due, _, _))| *due <= now)
+            .min_by_key(|(_, (due, order, _))| (*due, *order))?;
+
+        Some(self.queue.remove(index).2)
+    }
+}
+
+pub struct ConditionedTransport {
+    inner: Box<dyn Transport>,
+    conditions: LinkConditions,
+    rng: Rng,
+
+    outgoing: Delayed<(SocketAddr, Vec<u8>, Channel)>,
+    incoming: Delayed<TransportEvent>,
+}
+
+impl ConditionedTransport {
+    pub fn new(inner: Box<dyn Transport>, conditions: LinkConditions) -> Self {
+        Self {
+            inner,
+            conditions,
+            rng: Rng::new(conditions.seed),
+            outgoing: Delayed::new(),
+            incoming: Delayed::new(),
+        }
+    }
+
+    fn send_at(
+        &mut self,
+        dst: SocketAddr,
+        payload: Vec<u8>,
+        channel: Channel,
+        now: Instant
+    ) -> Result<()> {
+        for due in self.conditions.schedule(&mut self.rng, now, channel) {
+            self.outgoing.push(due, (dst, payload.clone(), channel));
+        }
+        self.flush(now)
+    }
+
+    fn flush(&mut self, now: Instant) -> Result<()> {
+        while let Some((dst, payload, channel)) = self.outgoing.pop_due(now) {
+            self.inner.send(dst, payload, channel)?;
+        }
+        Ok(())
+    }
+
+    fn recv_at(&mut self, now: Instant) -> Option<TransportEvent> {
+        if self.flush(now).is_err() {
+            println!("Could not send delayed packet");
+        }
+
+        // Everything that has arrived so far gets its own arrival time.
+        while let Some(event) = self.inner.recv() {
+            match event {
+                TransportEvent::Packet(_, _, channel) => {
+                    for due in self.conditions.schedule(&mut self.rng, now, channel) {
+                        self.incoming.push(due, event.clone());
+                    }
+                }
+
+                // Not packets, so nothing to delay.
+                event => self.incoming.push(now, event),
+            }
+        }
+
+        self.incoming.pop_due(now)
+    }
+}
+
+impl Transport for ConditionedTransport {
+    fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr()
+    }
+
+    fn send(&mut self, dst: SocketAddr, payload: Vec<u8>, channel: Channel) -> Result<()> {
+        self.send_at(dst, payload, channel, Instant::now())
+    }
+
+    fn recv(&mut self) -> Option<TransportEvent> {
+        self.recv_at(Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod link_conditioner_tests {
+    use crate::transport::MemoryNetwork;
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    // A conditioned transport on port 1 and a plain one on port 2.
+    fn link(network: &MemoryNetwork, conditions: LinkConditions) -> (ConditionedTransport, impl Transport) {
+        let conditioned = ConditionedTransport::new(Box::new(network.bind(addr(1))), conditions);
+        (conditioned, network.bind(addr(2)))
+    }
+
+    fn received(transport: &mut impl Transport) -> Vec<Vec<u8>> {
+        let mut payloads = vec![];
+        while let Some(TransportEvent::Packet(_, payload, _)) = transport.recv() {
+            payloads.push(payload);
+        }
+        payloads
+    }
+
+    #[test]
+    fn test_packets_are_held_back_by_the_delay() {
+        let network = MemoryNetwork::new();
+        let conditions = LinkConditions { delay_ms: 100, ..LinkConditions::default() };
+        let (mut conditioned, mut other) = link(&network, conditions);
+        let now = Instant::now();
+
+        conditioned.send_at(addr(2), vec![1], Channel::State, now).unwrap();
+        conditioned.flush(now + Duration::from_millis(99)).unwrap();
+        assert!(received(&mut other).is_empty());
+
+        conditioned.flush(now + Duration::from_millis(100)).unwrap();
+        assert_eq!(received(&mut other), vec![vec![1]]);
+    }
+
+    #[test]
+    fn test_incoming_packets_are_held_back_by_the_delay() {
+        let network = MemoryNetwork::new();
+        let conditions = LinkConditions { delay_ms: 100, ..LinkConditions::default() };
+        let (mut conditioned, mut other) = link(&network, conditions);
+        let now = Instant::now();
+
+        other.send(addr(1), vec![1], Channel::State).unwrap();
+
+        assert_eq!(conditioned.recv_at(now), None);
+        assert_eq!(
+            conditioned.recv_at(now + Duration::from_millis(100)),
+            Some(TransportEvent::Packet(addr(2), vec![1], Channel::State))
+        );
+    }
+
+    #[test]
+    fn test_everything_is_lost_at_full_loss() {
+        let network = MemoryNetwork::new();
+        let conditions = LinkConditions { loss_percent: 100, ..LinkConditions::default() };
+        let (mut conditioned, mut other) = link(&network, conditions);
+
+        for i in 0..10 {
+            conditioned.send(addr(2), vec![i], Channel::State).unwrap();
+        }
+
+        assert!(received(&mut other).is_empty());
+    }
+
+    #[test]
+    fn test_control_packets_are_only_delayed() {
+        let network = MemoryNetwork::new();
+        let conditions = LinkConditions {
+            delay_ms: 100,
+            jitter_ms: 50,
+            loss_percent: 50,
+            duplicate_percent: 50,
+            reorder_percent: 50,
+            seed: 3,
+        };
+        let (mut conditioned, mut other) = link(&network, conditions);
+        let now = Instant::now();
+
+        for i in 0..20 {
+            conditioned.send_at(addr(2), vec![i], Channel::Control, now).unwrap();
+            other.send(addr(1), vec![i], Channel::Control).unwrap();
+        }
+
+        assert_eq!(conditioned.recv_at(now), None);
+        conditioned.flush(now + Duration::from_millis(99)).unwrap();
+        assert!(received(&mut other).is_empty());
+
+        let later = now + Duration::from_millis(100);
+        conditioned.flush(later).unwrap();
+        let sent: Vec<Vec<u8>> = (0..20).map(|i| vec![i]).collect();
+        assert_eq!(received(&mut other), sent);
+
+        let mut arrived = vec![];
+        while let Some(TransportEvent::Packet(_, payload, _)) = conditioned.recv_at(later) {
+            arrived.push(payload);
+        }
+        assert_eq!(arrived, sent);
+    }
+
+    #[test]
+    fn test_everything_arrives_twice_at_full_duplication() {
+        let network = MemoryNetwork::new();
+        let conditions = LinkConditions { duplicate_percent: 100, ..LinkConditions::default() };
+        let (mut conditioned, mut other) = link(&network, conditions);
+
+        conditioned.send(addr(2), vec![1], Channel::State).unwrap();
+
+        assert_eq!(received(&mut other), vec![vec![1], vec![1]]);
+    }
+
+    #[test]
+    fn test_reordered_packets_arrive_after_later_ones() {
+        let network = MemoryNetwork::new();
+        let reordered = LinkConditions { reorder_percent: 100, ..LinkConditions::default() };
+        let (mut conditioned, mut other) = link(&network, reordered);
+        let now = Instant::now();
+
+        conditioned.send_at(addr(2), vec![1], Channel::State, now).unwrap();
+        conditioned.conditions.reorder_percent = 0;
+        conditioned.send_at(addr(2), vec![2], Channel::State, now).unwrap();
+        conditioned.flush(now + Duration::from_millis(REORDER_DELAY_MS)).unwrap();
+
+        assert_eq!(received(&mut other), vec![vec![2], vec![1]]);
+    }
+
+    #[test]
+    fn test_same_seed_gives_same_conditions() {
+        let conditions = LinkConditions {
+            delay_ms: 50,
+            jitter_ms: 30,
+            loss_percent: 20,
+            duplicate_percent: 10,
+            reorder_percent: 10,
+            seed: 18,
+        };
+        let now = Instant::now();
+
+        let schedule = |mut rng: Rng| -> Vec<Vec<Instant>> {
+            (0..100).map(|_| conditions.schedule(&mut rng, now, Channel::State)).collect()
+        };
+
+        assert_eq!(schedule(Rng::new(18)), schedule(Rng::new(18)));
+        assert_ne!(schedule(Rng::new(18)), schedule(Rng::new(19)));
+    }
+
+    #[test]
+    fn test_link_opts_take_percentages_up_to_100() {
+        let opts = LinkOpts::try_parse_from(["pong", "--loss", "100", "--delay", "80"]).unwrap();
+        assert_eq!(opts.link_conditions().loss_percent, 100);
+        assert_eq!(opts.link_conditions().delay_ms, 80);
+
+        assert!(LinkOpts::try_parse_from(["pong", "--reorder", "101"]).is_err());
+        assert!(LinkOpts::try_parse_from(["pong", "--duplicate", "-1"]).is_err());
+    }
+
+    #[test]
+    fn test_jitter_stays_within_bounds() {
+        let conditions = LinkConditions { delay_ms: 50, jitter_ms: 20, ..LinkConditions::default() };
+        let mut rng = Rng::new(0);
+
+        for _ in 0..1000 {
+            let delay = conditions.delay(&mut rng);
+            assert!(delay >= Duration::from_millis(30) && delay <= Duration::from_millis(70));
+        }
+    }
+}
@@ -0,0 +1,308 @@
+// Players waiting for a match.
+//
+// Everyone who says hi without being in a match joins the queue. Whenever two
+// or more are waiting they're paired up and handed off to a new match. The one
+// that has waited the longest always gets to play next, how its opponent is
+// picked depends on the pairing.
+
+use crate::error::Result;
+use crate::game_constants::{MS_PER_PING, PING_PAIRING_WAIT_MS};
+use crate::net_messages::ServerMsg;
+use crate::server_network::ServerNet;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Pairing {
+    // The two that have waited the longest.
+    FirstCome,
+
+    // Whoever is waiting with the round trip time closest to the one that has
+    // waited the longest, so that neither gets an edge. Players are held back
+    // until we have measured them, or for PING_PAIRING_WAIT_MS at most. Those
+    // we still haven't measured by then are paired first come first served.
+    ClosestPing,
+}
+
+#[derive(Debug)]
+struct Waiting {
+    addr: SocketAddr,
+    joined: Instant,
+
+    // New players are pinged right away, so that we know their round trip
+    // time as soon as possible.
+    pinged: bool,
+
+    // So that players only hear about their place in the queue when it changes.
+    told_position: Option<u32>,
+}
+
+#[derive(Debug)]
+pub struct Queue {
+    pairing: Pairing,
+
+    // Longest waiting first.
+    waiting: VecDeque<Waiting>,
+
+    // Keeps track of when its time to ping the waiting players, so that they
+    // don't time out.
+    last_ping: Option<Instant>,
+}
+
+impl Queue {
+    pub fn new(pairing: Pairing) -> Self {
+        Self { pairing, waiting: VecDeque::new(), last_ping: None }
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+
+    pub fn contains(&self, addr: SocketAddr) -> bool {
+        self.waiting.iter().any(|waiting| waiting.addr == addr)
+    }
+
+    // Saying hi twice doesn't get anyone two places in the queue.
+    pub fn join(&mut self, addr: SocketAddr) {
+        if !self.contains(addr) {
+            let joined = Instant::now();
+            self.waiting.push_back(Waiting { addr, joined, pinged: false, told_position: None });
+        }
+    }
+
+    pub fn leave(&mut self, addr: SocketAddr) {
+        self.waiting.retain(|waiting| waiting.addr != addr);
+    }
+
+    // Takes the players that can start playing out of the queue, two by two.
+    // The one that has waited the longest is player 1.
+    pub fn pair<F>(&mut self, rtt_ms: F, now: Instant) -> Vec<(SocketAddr, SocketAddr)>
+    where
+        F: Fn(SocketAddr) -> Option<u64>,
+    {
+        let mut pairs = vec![];
+
+        loop {
+            let ready: Vec<usize> = (0..self.waiting.len())
+                .filter(|&index| self.is_ready(&self.waiting[index], &rtt_ms, now))
+                .collect();
+
+            // Nobody gets to skip ahead of the one that has waited the longest.
+            if ready.len() < 2 || ready[0] != 0 {
+                return pairs;
+            }
+
+            let p1 = self.waiting[0].addr;
+            let opponent = self.opponent_for(p1, &ready[1..], &rtt_ms);
+            let p2 = self.waiting.remove(opponent).unwrap().addr;
+            self.waiting.pop_front();
+            pairs.push((p1, p2));
+        }
+    }
+
+    fn is_ready<F>(&self, waiting: &Waiting, rtt_ms: &F, now: Instant) -> bool
+    where
+        F: Fn(SocketAddr) -> Option<u64>,
+    {
+        match self.pairing {
+            Pairing::FirstCome => true,
+            Pairing::ClosestPing => {
+                rtt_ms(waiting.addr).is_some()
+                    || now.duration_since(waiting.joined) >= Duration::from_millis(PING_PAIRING_WAIT_MS)
+            }
+        }
+    }
+
+    // Where in the queue the opponent of p1 is, out of those ready to play.
+    fn opponent_for<F>(&self, p1: SocketAddr, ready: &[usize], rtt_ms: &F) -> usize
+    where
+        F: Fn(SocketAddr) -> Option<u64>,
+    {
+        match (self.pairing, rtt_ms(p1)) {
+            (Pairing::ClosestPing, Some(p1_rtt)) => ready
+                .iter()
+                .copied()
+                .min_by_key(|&index| {
+                    rtt_ms(self.waiting[index].addr).map_or(u64::MAX, |rtt| rtt.abs_diff(p1_rtt))
+                })
+                .unwrap_or(ready[0]),
+            _ => ready[0],
+        }
+    }
+
+    // Tells the players whose place in the queue changed where they are now.
+    // Players we can't send to are let go of, the rest keep waiting.
+    pub fn update(&mut self, net: &mut ServerNet) {
+        let ping_due = match self.last_ping {
+            Some(last_ping) => last_ping.elapsed() >= Duration::from_millis(MS_PER_PING),
+            None => true,
+        };
+
+        let mut unreachable = vec![];
+        for (index, waiting) in self.waiting.iter_mut().enumerate() {
+            if let Err(err) = waiting.update(net, index as u32 + 1, ping_due) {
+                println!("Dropping {} from the queue: {}", waiting.addr, err);
+                unreachable.push(waiting.addr);
+            }
+        }
+        self.waiting.retain(|waiting| !unreachable.contains(&waiting.addr));
+
+        if ping_due {
+            self.last_ping = Some(Instant::now());
+        }
+    }
+}
+
+impl Waiting {
+    fn update(&mut self, net: &mut ServerNet, position: u32, ping_due: bool) -> Result<()> {
+        if self.told_position != Some(position) {
+            net.send(self.addr, ServerMsg::Queued(position))?;
+            self.told_position = Some(position);
+        }
+
+        if ping_due || !self.pinged {
+            net.ping(self.addr, 0)?;
+            self.pinged = true;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod matchmaking_tests {
+    use crate::test_helper::*;
+    use crate::transport::{MemoryNetwork, Transport};
+    use std::collections::HashMap;
+    use super::*;
+
+    fn players(count: u16) -> Vec<SocketAddr> {
+        (0..count).map(|i| addr(&format!("127.0.0.1:{}", 45456 + i))).collect()
+    }
+
+    #[test]
+    fn test_first_come_pairs_in_order() {
+        let players = players(5);
+        let mut queue = Queue::new(Pairing::FirstCome);
+        for &player in &players {
+            queue.join(player);
+        }
+
+        let pairs = queue.pair(|_| None, Instant::now());
+
+        assert_eq!(pairs, vec![(players[0], players[1]), (players[2], players[3])]);
+        assert!(queue.contains(players[4]));
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn test_joining_twice_and_leaving() {
+        let players = players(2);
+        let mut queue = Queue::new(Pairing::FirstCome);
+
+        queue.join(players[0]);
+        queue.join(players[0]);
+        assert_eq!(queue.len(), 1);
+        assert!(queue.pair(|_| None, Instant::now()).is_empty());
+
+        queue.join(players[1]);
+        queue.leave(players[0]);
+        assert!(queue.pair(|_| None, Instant::now()).is_empty());
+        assert!(queue.contains(players[1]));
+    }
+
+    #[test]
+    fn test_closest_ping_pairs_similar_players() {
+        let players = players(4);
+        let rtts: HashMap<SocketAddr, u64> = vec![
+            (players[0], 40),
+            (players[1], 200),
+            (players[2], 45),
+            (players[3], 190),
+        ].into_iter().collect();
+
+        let mut queue = Queue::new(Pairing::ClosestPing);
+        for &player in &players {
+            queue.join(player);
+        }
+
+        let pairs = queue.pair(|addr| rtts.get(&addr).copied(), Instant::now());
+
+        assert_eq!(pairs, vec![(players[0], players[2]), (players[1], players[3])]);
+    }
+
+    #[test]
+    fn test_closest_ping_waits_for_measurements() {
+        let players = players(3);
+        let mut queue = Queue::new(Pairing::ClosestPing);
+        for &player in &players {
+            queue.join(player);
+        }
+        let now = Instant::now();
+
+        // Player 1 has waited the longest, nobody is paired before it can be.
+        let rtt = |addr| if addr == players[0] { None } else { Some(50) };
+        assert!(queue.pair(rtt, now).is_empty());
+
+        let rtt = |addr| if addr == players[1] { None } else { Some(50) };
+        assert_eq!(queue.pair(rtt, now), vec![(players[0], players[2])]);
+    }
+
+    #[test]
+    fn test_closest_ping_without_measurements_is_first_come_in_the_end() {
+        let players = players(3);
+        let mut queue = Queue::new(Pairing::ClosestPing);
+        for &player in &players {
+            queue.join(player);
+        }
+
+        let now = Instant::now();
+        assert!(queue.pair(|_| None, now).is_empty());
+
+        let later = now + Duration::from_millis(PING_PAIRING_WAIT_MS);
+        assert_eq!(queue.pair(|_| None, later), vec![(players[0], players[1])]);
+    }
+
+    #[test]
+    fn test_players_are_told_when_their_position_changes() {
+        let network = MemoryNetwork::new();
+        let mut net = memory_server_net(&network);
+        let mut first = network.bind(addr("127.0.0.1:45456"));
+        let mut second = network.bind(addr("127.0.0.1:45457"));
+        let mut queue = Queue::new(Pairing::FirstCome);
+
+        queue.join(first.local_addr());
+        queue.join(second.local_addr());
+        queue.update(&mut net);
+        queue.update(&mut net);
+
+        let queued = |msgs: Vec<ServerMsg>| -> Vec<ServerMsg> {
+            msgs.into_iter().filter(|msg| matches!(msg, ServerMsg::Queued(_))).collect()
+        };
+        assert_eq!(queued(received_server_msgs(&mut first)), vec![ServerMsg::Queued(1)]);
+        assert_eq!(queued(received_server_msgs(&mut second)), vec![ServerMsg::Queued(2)]);
+
+        queue.leave(first.local_addr());
+        queue.update(&mut net);
+        assert_eq!(queued(received_server_msgs(&mut second)), vec![ServerMsg::Queued(1)]);
+    }
+
+    #[test]
+    fn test_players_that_cant_be_reached_are_let_go_of() {
+        let network = MemoryNetwork::new();
+        let players = players(2);
+        let mut net = unreachable_server_net(&network, players[0]);
+        let mut queue = Queue::new(Pairing::FirstCome);
+
+        queue.join(players[0]);
+        queue.join(players[1]);
+        queue.update(&mut net);
+
+        assert!(!queue.contains(players[0]));
+        assert!(queue.contains(players[1]));
+    }
+}
@@ -1,42 +1,231 @@
-use crate::pong_state::PongState;
+use crate::delta::StateDelta;
+use crate::pong_state::{PongState, PlayerMovement};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
-#[derive(Debug, Serialize, Deserialize)]
+// Bump whenever the protocol changes. Peers on different versions are told
+// apart during the handshake.
+pub const PROTOCOL_VERSION: u8 = 7;
+
+// Longer player names are cut short.
+pub const MAX_NAME_BYTES: usize = 16;
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
 pub enum PlayerOrder {
     P1,
     P2,
 }
 
+impl PlayerOrder {
+    pub fn opponent(self) -> Self {
+        match self {
+            PlayerOrder::P1 => PlayerOrder::P2,
+            PlayerOrder::P2 => PlayerOrder::P1,
+        }
+    }
+}
+
+// Handed to each player when a match starts. A player that lost contact can
+// present it, from any address, to take its place in the match again.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct SessionToken(pub u64);
+
+// The final outcome of a match, sent to both players once someone has won.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MatchResult {
+    pub winner: PlayerOrder,
+    pub p1_score: u32,
+    pub p2_score: u32,
+}
+
+
+// A change in how a player moves. Clients only send these when their movement
+// changes, numbered so that the server can skip old or duplicated ones.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MoveInput {
+    pub seq: u32,
+    pub movement: PlayerMovement,
+
+    // The server tick the change is for, the one the client expects the
+    // server to be on when the change arrives. 0 if it doesn't know yet.
+    pub tick: u32,
+}
+
+// How far the server has come with a player's movement changes: the latest
+// change it applied, and for how many ticks it has applied it. Lets a client
+// tell which of its own predicted ticks the server hasn't seen yet.
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct InputAck {
+    pub seq: u32,
+    pub ticks: u32,
+}
+
+// Optional parts of the protocol a client can deal with. The server only uses
+// the ones both sides have.
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Capabilities {
+    // Can rebuild states from StateDelta messages.
+    pub state_deltas: bool,
+}
+
+impl Capabilities {
+    // Everything this version of the game knows about.
+    pub fn all() -> Self {
+        Self { state_deltas: true }
+    }
+
+    pub fn shared_with(self, other: Self) -> Self {
+        Self { state_deltas: self.state_deltas && other.state_deltas }
+    }
+}
+
+// The first thing a client says to the server.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Hello {
+    pub version: u8,
+    pub capabilities: Capabilities,
+
+    // What to call the player, empty if it didn't say.
+    pub name: String,
+}
+
+impl Hello {
+    pub fn new() -> Self {
+        Self { version: PROTOCOL_VERSION, capabilities: Capabilities::all(), name: String::new() }
+    }
+
+    pub fn with_name(name: &str) -> Self {
+        Self { name: clean_name(name), ..Self::new() }
+    }
+}
+
+// Names are shown to other clients, so control characters are dropped and
+// long names are cut short, without splitting a character in half.
+pub fn clean_name(name: &str) -> String {
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim();
+
+    let mut end = name.len().min(MAX_NAME_BYTES);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    name[..end].to_string()
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Why the server won't talk to a client.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+pub enum RejectReason {
+    UnsupportedVersion { server_version: u8 },
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RejectReason::UnsupportedVersion { server_version } => write!(
+                f,
+                "The server speaks protocol version {}, this client speaks version {}. \
+                 Both need to run the same version of the game.",
+                server_version, PROTOCOL_VERSION
+            ),
+        }
+    }
+}
+
+// Sent with every ping so that clients can keep track of the server clock.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ClockSync {
+    // The tick of the running match, 0 when there is none.
+    pub tick: u32,
+
+    // Server time in ms when the ping was sent. Clients echo it in their pong.
+    pub time_ms: u64,
 
-#[derive(Debug, Serialize, Deserialize)]
+    // The latest round trip time the server measured to this client, if any.
+    pub rtt_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub enum ServerMsg {
 
     // These are communicated FROM the server TO the client.
 
     Hi,
 
-    Ping,
-    Timeout,
-    Disconnect,
-    Connect,
+    // Answers to a client's Hi, with the capabilities both sides share.
+    Accept(Capabilities),
+    Reject(RejectReason),
+
+    Ping(ClockSync),
+
+    Start(PlayerOrder, SessionToken),
+    State(PongState, InputAck),
 
-    Start(PlayerOrder),
-    State(PongState),
+    // Only what changed since a state the client has acknowledged.
+    StateDelta(StateDelta, InputAck),
+    MatchOver(MatchResult),
     Abort,
+
+    // The opponent lost contact. The match goes on if they're back within
+    // this many seconds, otherwise it's aborted.
+    Paused(u32),
+
+    // Waiting for an opponent, this far back in the queue. 1 is next in line.
+    Queued(u32),
+
+    // Sent to a spectator when it starts watching a match, with the names of
+    // player 1 and player 2.
+    Watching(String, String),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub enum ClientMsg {
 
     // These are communicated FROM the client TO the server.
 
-    Hi,
+    Hi(Hello),
 
-    Pong,
+    // Echoes the time_ms of the ping being answered.
+    Pong(u64),
+
+    // Noticed by the server's transport, never sent over the wire.
     Timeout,
     Connect,
     Disconnect,
 
-    MoveUp,
-    MoveDown,
+    Move(MoveInput),
+
+    // We got the state with this tick, the server can send deltas against it.
+    AckState(u32),
+
+    // Says hi again, possibly from a new address, to get back into a match.
+    Resume(Hello, SessionToken),
+
+    // Asks to watch a match rather than play in one.
+    Spectate(Hello),
+}
+
+#[cfg(test)]
+mod net_messages_tests {
+    use super::*;
+
+    #[test]
+    fn test_long_names_are_cut_short() {
+        assert_eq!(Hello::with_name("Bob").name, "Bob");
+        assert_eq!(Hello::with_name("A name way too long to fit").name, "A name way too l");
+
+        // Without splitting a character in half.
+        assert_eq!(Hello::with_name("ååååååååå").name, "åååååååå");
+    }
+
+    #[test]
+    fn test_control_characters_are_dropped_from_names() {
+        assert_eq!(clean_name(" Bo\nb\u{1b}[2J "), "Bob[2J");
+        assert_eq!(clean_name("\t\r\n"), "");
+    }
 }
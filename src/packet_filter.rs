@@ -0,0 +1,235 @@
+// Screens packets from clients before the server looks at them. Oversized
+// packets, packets from addresses sending too much and packets that don't
+// decode are counted and dropped instead of taking the server down.
+
+use crate::codec::{decode_client_msg, DecodeError};
+use crate::net_messages::ClientMsg;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::game_constants::{
+    MAX_CLIENT_PAYLOAD_BYTES,
+    MAX_CLIENT_PACKETS_PER_SECOND,
+};
+
+// At most this many addresses are tracked, so a flood from spoofed addresses
+// can't eat all memory. When they're all taken, addresses we haven't heard from
+// in the last second are forgotten, at most once a second. Until there's room,
+// packets from new addresses are dropped like any other flood.
+const MAX_TRACKED_ADDRS: usize = 1024;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Dropped {
+    Oversized(usize),
+    RateLimited,
+    Malformed(DecodeError),
+}
+
+// How many packets have been dropped so far, and why.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct DropCounts {
+    pub oversized: u64,
+    pub rate_limited: u64,
+    pub malformed: u64,
+}
+
+impl DropCounts {
+    pub fn total(&self) -> u64 {
+        self.oversized + self.rate_limited + self.malformed
+    }
+}
+
+// Packets received from one address during the current second.
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    packets: u32,
+}
+
+#[derive(Debug)]
+pub struct PacketFilter {
+    windows: HashMap<SocketAddr, Window>,
+    last_sweep: Option<Instant>,
+    dropped: DropCounts,
+}
+
+impl PacketFilter {
+    pub fn new() -> Self {
+        Self { windows: HashMap::new(), last_sweep: None, dropped: DropCounts::default() }
+    }
+
+    pub fn dropped(&self) -> DropCounts {
+        self.dropped
+    }
+
+    pub fn check(
+        &mut self,
+        addr: SocketAddr,
+        payload: &[u8],
+        now: Instant
+    ) -> Result<ClientMsg, Dropped> {
+        let result = self.screen(addr, payload, now);
+
+        match result {
+            Err(Dropped::Oversized(_)) => self.dropped.oversized += 1,
+            Err(Dropped::RateLimited) => self.dropped.rate_limited += 1,
+            Err(Dropped::Malformed(_)) => self.dropped.malformed += 1,
+            Ok(_) => {}
+        }
+        result
+    }
+
+    // Cheapest checks first.
+    fn screen(&mut self, addr: SocketAddr, payload: &[u8], now: Instant) -> Result<ClientMsg, Dropped> {
+        if payload.len() > MAX_CLIENT_PAYLOAD_BYTES {
+            return Err(Dropped::Oversized(payload.len()));
+        }
+
+        if !self.within_rate(addr, now) {
+            return Err(Dropped::RateLimited);
+        }
+
+        decode_client_msg(payload).map_err(Dropped::Malformed)
+    }
+
+    fn within_rate(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        let second = Duration::from_secs(1);
+
+        if self.windows.len() >= MAX_TRACKED_ADDRS && !self.windows.contains_key(&addr) {
+            let sweep_due = match self.last_sweep {
+                Some(last_sweep) => now.duration_since(last_sweep) >= second,
+                None => true,
+            };
+            if sweep_due {
+                self.windows.retain(|_, window| now.duration_since(window.start) < second);
+                self.last_sweep = Some(now);
+            }
+
+            if self.windows.len() >= MAX_TRACKED_ADDRS {
+                return false;
+            }
+        }
+
+        let window = self.windows.entry(addr).or_insert(Window { start: now, packets: 0 });
+        if now.duration_since(window.start) >= second {
+            *window = Window { start: now, packets: 0 };
+        }
+
+        window.packets += 1;
+        window.packets <= MAX_CLIENT_PACKETS_PER_SECOND
+    }
+}
+
+impl Default for PacketFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod packet_filter_tests {
+    use crate::codec::encode_client_msg;
+    use crate::net_messages::PROTOCOL_VERSION;
+    use crate::rng::Rng;
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn test_valid_packets_pass() {
+        let mut filter = PacketFilter::new();
+        let payload = encode_client_msg(&ClientMsg::AckState(42));
+
+        assert_eq!(filter.check(addr(1), &payload, Instant::now()), Ok(ClientMsg::AckState(42)));
+        assert_eq!(filter.dropped().total(), 0);
+    }
+
+    #[test]
+    fn test_oversized_packets_are_dropped() {
+        let mut filter = PacketFilter::new();
+        let payload = vec![0; MAX_CLIENT_PAYLOAD_BYTES + 1];
+
+        let result = filter.check(addr(1), &payload, Instant::now());
+
+        assert_eq!(result, Err(Dropped::Oversized(MAX_CLIENT_PAYLOAD_BYTES + 1)));
+        assert_eq!(filter.dropped().oversized, 1);
+    }
+
+    #[test]
+    fn test_too_many_packets_from_one_address_are_dropped() {
+        let mut filter = PacketFilter::new();
+        let payload = encode_client_msg(&ClientMsg::AckState(1));
+        let now = Instant::now();
+
+        for _ in 0..MAX_CLIENT_PACKETS_PER_SECOND {
+            assert!(filter.check(addr(1), &payload, now).is_ok());
+        }
+        assert_eq!(filter.check(addr(1), &payload, now), Err(Dropped::RateLimited));
+
+        // Others aren't affected, and the limit starts over the next second.
+        assert!(filter.check(addr(2), &payload, now).is_ok());
+        assert!(filter.check(addr(1), &payload, now + Duration::from_secs(1)).is_ok());
+        assert_eq!(filter.dropped().rate_limited, 1);
+    }
+
+    #[test]
+    fn test_garbage_is_counted_and_dropped() {
+        let mut filter = PacketFilter::new();
+        let mut rng = Rng::new(15);
+        let now = Instant::now();
+
+        for port in 0..10_000u32 {
+            let len = rng.range(0, MAX_CLIENT_PAYLOAD_BYTES as i32) as usize;
+            let mut payload: Vec<u8> = (0..len).map(|_| rng.next_u64() as u8).collect();
+
+            // Get past the version check most of the time.
+            if len > 0 && rng.range(0, 3) > 0 {
+                payload[0] = PROTOCOL_VERSION;
+            }
+
+            // Doesn't matter what comes out, as long as nothing panics.
+            let _ = filter.check(addr(port as u16), &payload, now);
+        }
+
+        assert!(filter.dropped().malformed > 0);
+    }
+
+    #[test]
+    fn test_tracked_addresses_are_bounded() {
+        let mut filter = PacketFilter::new();
+        let payload = encode_client_msg(&ClientMsg::AckState(1));
+        let now = Instant::now();
+
+        for port in 0..(MAX_TRACKED_ADDRS as u16) {
+            filter.check(addr(port), &payload, now).unwrap();
+        }
+
+        let later = now + Duration::from_secs(2);
+        filter.check(addr(60000), &payload, later).unwrap();
+
+        assert_eq!(filter.windows.len(), 1);
+    }
+
+    #[test]
+    fn test_new_addresses_are_dropped_while_all_are_tracked() {
+        let mut filter = PacketFilter::new();
+        let payload = encode_client_msg(&ClientMsg::AckState(1));
+        let now = Instant::now();
+
+        for port in 0..(MAX_TRACKED_ADDRS as u16) {
+            filter.check(addr(port), &payload, now).unwrap();
+        }
+
+        for port in 0..100 {
+            let result = filter.check(addr(50000 + port), &payload, now);
+            assert_eq!(result, Err(Dropped::RateLimited));
+        }
+        assert_eq!(filter.windows.len(), MAX_TRACKED_ADDRS);
+
+        // Those already tracked can go on.
+        assert!(filter.check(addr(0), &payload, now).is_ok());
+    }
+}
@@ -4,13 +4,22 @@ use crate::game_constants::{
     HALF_PADDLE_WIDTH,
     BALL_WIDTH,
     BALL_HEIGHT,
+    BALL_START_VEL,
+    BALL_SPEED_UP,
+    BALL_MAX_SPEED,
+    BALL_MAX_VERTICAL_SPEED,
     GAME_WIDTH,
     GAME_HEIGHT,
     PLAYER_MOVE_UNIT,
     P1_X_POS,
     P2_X_POS,
+    POINTS_TO_WIN,
+    WIN_BY,
+    SERVE_DELAY_TICKS,
 };
-use ggez::graphics::Rect;
+use crate::geometry::{time_to_reach, MoveTime, Rect, Vec2};
+use crate::net_messages::PlayerOrder;
+use crate::rng::Rng;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
@@ -39,10 +48,68 @@ pub struct PongState {
 
     // velocity vector
     pub ball_vel: (i32, i32),
+
+    // Points scored by each player so far in the match.
+    pub p1_score: u32,
+    pub p2_score: u32,
+
+    // How many points are needed to win the match. The winner also needs to
+    // be at least WIN_BY points ahead.
+    pub points_to_win: u32,
+
+    // The player serving the current ball. Whoever concedes a point serves
+    // the next one, towards the opponent.
+    pub serving: PlayerOrder,
+
+    // Ticks left until a served ball starts moving.
+    pub serve_delay: u32,
+
+    // How many ticks the match has been running. It only ever goes up, so it
+    // works as the clock of the match.
+    pub ticks: u32,
+
+    // All randomness in the game comes from here, e g the serve angles. It's
+    // part of the state so that clients extrapolating past a point serve the
+    // same way as the server, and so that a match can be replayed.
+    pub rng: Rng,
+}
+
+// Used when no seed is given, e g by clients before they get a real state.
+const DEFAULT_SEED: u64 = 0;
+
+// What the ball can collide with.
+#[derive(Debug, Copy, Clone)]
+enum Surface {
+    Wall,
+
+    // The vertical position of the paddle that was hit.
+    Paddle(i32),
+}
+
+// A collision somewhere along the ball's path during one tick.
+#[derive(Debug, Copy, Clone)]
+struct Contact {
+    time: MoveTime,
+    surface: Surface,
+}
+
+impl Default for PongState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl PongState {
     pub fn new() -> Self {
+        Self::with_points_to_win(POINTS_TO_WIN)
+    }
+
+    pub fn with_points_to_win(points_to_win: u32) -> Self {
+        Self::new_seeded(points_to_win, DEFAULT_SEED)
+    }
+
+    // The same seed and the same player movements always play out the same.
+    pub fn new_seeded(points_to_win: u32, seed: u64) -> Self {
         Self {
             p1: 0,
             p1_move: None,
@@ -51,10 +118,39 @@ impl PongState {
             p2_move: None,
 
             ball: (GAME_WIDTH/2, GAME_HEIGHT/2),
-            ball_vel: (5, 7),
+            ball_vel: BALL_START_VEL,
+
+            p1_score: 0,
+            p2_score: 0,
+            points_to_win,
+
+            // The first ball is served right away, P1 towards P2.
+            serving: PlayerOrder::P1,
+            serve_delay: 0,
+            ticks: 0,
+            rng: Rng::new(seed),
+        }
+    }
+
+    // Returns the player who has won the match, if any.
+    pub fn winner(&self) -> Option<PlayerOrder> {
+        let has_won = |score: u32, other: u32| {
+            score >= self.points_to_win && score >= other + WIN_BY
+        };
+
+        if has_won(self.p1_score, self.p2_score) {
+            Some(PlayerOrder::P1)
+        } else if has_won(self.p2_score, self.p1_score) {
+            Some(PlayerOrder::P2)
+        } else {
+            None
         }
     }
 
+    pub fn is_match_over(&self) -> bool {
+        self.winner().is_some()
+    }
+
     // We override the saved movement state for player 1 based provided (local)
     // information. This will make the clients own paddle be more up to date.
     pub fn extrapolate_p1(&mut self, p1_move: PlayerMovement) {
@@ -75,19 +171,24 @@ impl PongState {
         p1_move: Option<PlayerMovement>,
         p2_move: Option<PlayerMovement>
     ) {
-        self.update_player_movements(p1_move, p2_move);
-        self.update_ball_movement();
-
-        if self.ball_passed_a_paddle() {
-            self.reset();
+        // Nothing moves once someone has won.
+        if self.is_match_over() {
+            return;
         }
 
-        if self.ball_touching_top_or_bottom() {
-            self.vertically_bounce_ball();
+        self.ticks += 1;
+        self.update_player_movements(p1_move, p2_move);
+
+        // A served ball stays put for a while so players can get ready.
+        if self.serve_delay > 0 {
+            self.serve_delay -= 1;
+        } else {
+            self.update_ball_movement();
         }
 
-        if self.ball_touching_any_paddle() {
-            self.horizontally_bounce_ball();
+        if let Some(scorer) = self.ball_passed_a_paddle() {
+            self.award_point(scorer);
+            self.serve(scorer.opponent());
         }
     }
 
@@ -122,67 +223,145 @@ impl PongState {
         }
     }
 
-    fn reset(&mut self) {
+    // Puts the ball back in the middle, heading away from the serving player
+    // at the initial speed.
+    fn serve(&mut self, serving: PlayerOrder) {
         self.ball = (GAME_WIDTH/2, GAME_HEIGHT/2);
+        self.serving = serving;
+        self.serve_delay = SERVE_DELAY_TICKS;
+
+        let vel_x = match serving {
+            PlayerOrder::P1 => BALL_START_VEL.0,
+            PlayerOrder::P2 => -BALL_START_VEL.0,
+        };
+        let vel_y = self.rng.range(-BALL_START_VEL.1, BALL_START_VEL.1);
+        self.ball_vel = (vel_x, vel_y);
     }
 
-    fn ball_passed_a_paddle(&self) -> bool {
-        self.ball.0 > GAME_WIDTH || (self.ball.0 + BALL_WIDTH) < 0
+    // Returns the player who scored if the ball has left the field.
+    fn ball_passed_a_paddle(&self) -> Option<PlayerOrder> {
+        if self.ball.0 > GAME_WIDTH {
+            Some(PlayerOrder::P1)
+        } else if (self.ball.0 + BALL_WIDTH) < 0 {
+            Some(PlayerOrder::P2)
+        } else {
+            None
+        }
     }
 
-    fn ball_touching_top_or_bottom(&self) -> bool {
-        (self.ball.1 + BALL_HEIGHT) > GAME_HEIGHT || self.ball.1 < 0
+    fn award_point(&mut self, scorer: PlayerOrder) {
+        match scorer {
+            PlayerOrder::P1 => self.p1_score += 1,
+            PlayerOrder::P2 => self.p2_score += 1,
+        }
     }
 
-    fn ball_touching_any_paddle(&mut self) -> bool {
-        // Cheats alert:
-        // Use ggez Rect in order to make use of their collision detection
-        let ball = Rect::new(
-            self.ball.0 as f32,
-            self.ball.1 as f32,
-            BALL_WIDTH as f32,
-            BALL_HEIGHT as f32,
-        );
+    // Finds where the ball first hits a wall during this tick, if it does.
+    fn wall_contact(&self) -> Option<Contact> {
+        let (y, vy) = (self.ball.1, self.ball_vel.1);
 
-        let left_paddle = Rect::new(
-            P1_X_POS as f32,
-            self.p1 as f32,
-            PADDLE_WIDTH as f32,
-            PADDLE_HEIGHT as f32,
-        );
+        let time = if vy < 0 {
+            time_to_reach(y, vy, 0)
+        } else {
+            time_to_reach(y + BALL_HEIGHT, vy, GAME_HEIGHT)
+        }?;
 
-        let right_paddle = Rect::new(
-            P2_X_POS as f32,
-            self.p2 as f32,
-            PADDLE_WIDTH as f32,
-            PADDLE_HEIGHT as f32,
-        );
+        Some(Contact { time, surface: Surface::Wall })
+    }
+
+    // Finds where the ball first hits the left paddle during this tick, if it
+    // does. The whole path is checked so a fast ball can't skip past it.
+    fn left_paddle_contact(&self) -> Option<Contact> {
+        let (x, vx) = (self.ball.0, self.ball_vel.0);
+
+        // Is the ball is moving towards the paddle, but not already passed it?
+        if vx >= 0 || x <= HALF_PADDLE_WIDTH {
+            return None;
+        }
+
+        let time = time_to_reach(x, vx, P1_X_POS + PADDLE_WIDTH)?;
+        let contact = Contact { time, surface: Surface::Paddle(self.p1) };
+        self.paddle_hit(contact, P1_X_POS, self.p1)
+    }
 
-        // Is the ball is moving towards a paddle and but hasn't yet passed it?
-        let towards_left_paddle =
-            self.ball_vel.0 < 0 && self.ball.0 > HALF_PADDLE_WIDTH;
+    // Same as left_paddle_contact, but the right edge of the ball is the one
+    // that hits the paddle.
+    fn right_paddle_contact(&self) -> Option<Contact> {
+        let (x, vx) = (self.ball.0 + BALL_WIDTH, self.ball_vel.0);
 
-        // Is the ball is moving towards a paddle and but hasn't yet passed it?
-        let towards_right_paddle =
-            self.ball_vel.0 > 0 && self.ball.0 < (GAME_WIDTH-HALF_PADDLE_WIDTH);
+        if vx <= 0 || x >= (GAME_WIDTH-HALF_PADDLE_WIDTH) {
+            return None;
+        }
+
+        let time = time_to_reach(x, vx, P2_X_POS)?;
+        let contact = Contact { time, surface: Surface::Paddle(self.p2) };
+        self.paddle_hit(contact, P2_X_POS, self.p2)
+    }
+
+    // The ball reaches the paddle's horizontal position at the contact, but
+    // it's only a hit if the paddle is there vertically as well.
+    fn paddle_hit(&self, contact: Contact, paddle_x: i32, paddle_y: i32) -> Option<Contact> {
+        let at = self.ball_position_at(contact.time);
+        let ball = Rect::new(at.x, at.y, BALL_WIDTH, BALL_HEIGHT);
+        let paddle = Rect::new(paddle_x, paddle_y, PADDLE_WIDTH, PADDLE_HEIGHT);
+
+        if ball.overlaps(&paddle) {
+            Some(contact)
+        } else {
+            None
+        }
+    }
 
-        // Need to make sure that we aren't inside/passed the paddle, because
-        // then we don't want collisions.
-        (towards_left_paddle && ball.overlaps(&left_paddle)) ||
-            (towards_right_paddle && ball.overlaps(&right_paddle))
+    fn ball_position_at(&self, time: MoveTime) -> Vec2 {
+        Vec2::from(self.ball).moved(Vec2::from(self.ball_vel), time)
     }
 
     fn vertically_bounce_ball(&mut self) {
         self.ball_vel.1 *= -1;
     }
 
-    fn horizontally_bounce_ball(&mut self) {
-        self.ball_vel.0 *= -1;
+    fn horizontally_bounce_ball(&mut self, paddle: i32) {
+        // Speed up a bit for every hit so that long rallies get harder.
+        let speed = std::cmp::min(self.ball_vel.0.abs() + BALL_SPEED_UP, BALL_MAX_SPEED);
+        self.ball_vel.0 = -self.ball_vel.0.signum() * speed;
+
+        // How far from the paddle center did the ball hit? The center gives a
+        // flat bounce and the edges give the steepest ones.
+        let ball_center = self.ball.1 + BALL_HEIGHT/2;
+        let paddle_center = paddle + PADDLE_HEIGHT/2;
+        let max_offset = PADDLE_HEIGHT/2;
+        let offset = std::cmp::max(
+            -max_offset,
+            std::cmp::min(max_offset, ball_center - paddle_center)
+        );
+
+        self.ball_vel.1 = offset * BALL_MAX_VERTICAL_SPEED / max_offset;
     }
 
     fn update_ball_movement(&mut self) {
-        self.ball.0 += self.ball_vel.0;
-        self.ball.1 += self.ball_vel.1;
+        // Only the first collision along the path is handled. The ball stops
+        // at the contact point and continues from there on the next tick.
+        let contact = [
+            self.wall_contact(),
+            self.left_paddle_contact(),
+            self.right_paddle_contact(),
+        ].iter().flatten().copied().min_by(|a, b| a.time.compare(&b.time));
+
+        match contact {
+            Some(contact) => {
+                self.ball = self.ball_position_at(contact.time).into();
+
+                match contact.surface {
+                    Surface::Wall => self.vertically_bounce_ball(),
+                    Surface::Paddle(paddle) => self.horizontally_bounce_ball(paddle),
+                }
+            }
+
+            None => {
+                self.ball.0 += self.ball_vel.0;
+                self.ball.1 += self.ball_vel.1;
+            }
+        }
     }
 }
 
@@ -202,11 +381,53 @@ mod player_tests {
         let mut state = PongState::new();
 
         state.ball.0 = -100;
-        assert_ne!(state, PongState::new());
+        assert_ne!(state.ball, PongState::new().ball);
 
 
         state.tick(None, None);
-        assert_eq!(state, PongState::new());
+        assert_eq!(state.ball, PongState::new().ball);
+    }
+
+    #[test]
+    fn test_tick_awards_point_to_the_opposite_player() {
+        let mut state = PongState::new();
+
+        // Passed p1, so p2 scores.
+        state.ball.0 = -100;
+        state.tick(None, None);
+        assert_eq!((state.p1_score, state.p2_score), (0, 1));
+
+        // Passed p2, so p1 scores.
+        state.ball.0 = GAME_WIDTH + 100;
+        state.tick(None, None);
+        assert_eq!((state.p1_score, state.p2_score), (1, 1));
+    }
+
+    #[test]
+    fn test_winner_needs_enough_points_and_a_lead() {
+        let mut state = PongState::with_points_to_win(3);
+        assert_eq!(state.winner(), None);
+
+        state.p1_score = 3;
+        state.p2_score = 2;
+        assert_eq!(state.winner(), None);
+
+        state.p1_score = 4;
+        assert_eq!(state.winner(), Some(PlayerOrder::P1));
+
+        state.p1_score = 1;
+        state.p2_score = 3;
+        assert_eq!(state.winner(), Some(PlayerOrder::P2));
+    }
+
+    #[test]
+    fn test_tick_does_nothing_when_match_is_over() {
+        let mut state = PongState::with_points_to_win(1);
+        state.p1_score = 2;
+
+        let before = state;
+        state.tick(Some(PlayerMovement::Down), Some(PlayerMovement::Down));
+        assert_eq!(state, before);
     }
 
     #[test]
@@ -215,15 +436,76 @@ mod player_tests {
 
         assert!(state.ball_vel.1 > 0);
 
-        state.ball.1 = -10;
+        state.ball_vel.1 = -7;
+        state.ball.1 = 2;
+        state.tick(None, None);
+
+        assert!(state.ball_vel.1 > 0);
+        assert_eq!(state.ball.1, 0);
+
+        state.ball.1 = GAME_HEIGHT - BALL_HEIGHT - 2;
         state.tick(None, None);
 
         assert!(state.ball_vel.1 < 0);
+        assert_eq!(state.ball.1, GAME_HEIGHT - BALL_HEIGHT);
+    }
+
+    #[test]
+    fn test_tick_stops_fast_ball_at_the_wall() {
+        let mut state = PongState::new();
 
-        state.ball.1 = 10000;
+        state.ball_vel = (1, -GAME_HEIGHT);
+        state.ball = (GAME_WIDTH/2, 100);
         state.tick(None, None);
 
-        assert!(state.ball_vel.1 > 0);
+        assert_eq!(state.ball.1, 0);
+        assert_eq!(state.ball_vel.1, GAME_HEIGHT);
+    }
+
+    #[test]
+    fn test_tick_fast_ball_does_not_tunnel_through_paddles() {
+        let mut state = PongState::new();
+
+        // Would end up far behind p1 without swept collisions.
+        state.ball_vel = (-GAME_WIDTH, 0);
+        state.ball = (200, state.p1 + 30);
+        state.tick(None, None);
+
+        assert_eq!(state.ball.0, PADDLE_WIDTH);
+        assert!(state.ball_vel.0 > 0);
+
+        // Same thing for p2.
+        state.ball_vel = (GAME_WIDTH, 0);
+        state.ball = (200, state.p2 + 30);
+        state.tick(None, None);
+
+        assert_eq!(state.ball.0 + BALL_WIDTH, P2_X_POS);
+        assert!(state.ball_vel.0 < 0);
+        assert_eq!((state.p1_score, state.p2_score), (0, 0));
+    }
+
+    #[test]
+    fn test_tick_fast_ball_passes_a_paddle_it_misses() {
+        let mut state = PongState::new();
+
+        state.ball_vel = (-GAME_WIDTH, 0);
+        state.ball = (200, state.p1 + PADDLE_HEIGHT + 100);
+        state.tick(None, None);
+
+        assert_eq!(state.p2_score, 1);
+    }
+
+    #[test]
+    fn test_tick_handles_the_earliest_collision() {
+        let mut state = PongState::new();
+
+        // Hits the top wall before reaching p1.
+        state.ball_vel = (-100, -100);
+        state.ball = (100, 20);
+        state.tick(None, None);
+
+        assert_eq!(state.ball, (80, 0));
+        assert_eq!(state.ball_vel, (-100, 100));
     }
 
     #[test]
@@ -237,7 +519,127 @@ mod player_tests {
         state.ball = (PADDLE_WIDTH - 1, state.p1);
         state.tick(None, None);
 
-        assert_eq!(state.ball_vel.0, 1);
+        assert_eq!(state.ball_vel.0, 1 + BALL_SPEED_UP);
+    }
+
+    #[test]
+    fn test_tick_bounces_flat_from_paddle_center() {
+        let mut state = PongState::new();
+
+        // Aim the ball so it is centered on p1 after moving.
+        state.ball_vel = (-1, 0);
+        state.ball = (PADDLE_WIDTH, PADDLE_HEIGHT/2 - BALL_HEIGHT/2);
+        state.tick(None, None);
+
+        assert_eq!(state.ball_vel.1, 0);
+    }
+
+    #[test]
+    fn test_tick_bounces_steep_from_paddle_edges() {
+        let mut state = PongState::new();
+
+        // Top edge of p1
+        state.ball_vel = (-1, 0);
+        state.ball = (PADDLE_WIDTH, -BALL_HEIGHT/2);
+        state.tick(None, None);
+
+        assert_eq!(state.ball_vel.1, -BALL_MAX_VERTICAL_SPEED);
+
+        // Bottom edge of p2
+        state.ball_vel = (1, 0);
+        state.ball = (P2_X_POS - BALL_WIDTH, state.p2 + PADDLE_HEIGHT - BALL_HEIGHT/2);
+        state.tick(None, None);
+
+        assert_eq!(state.ball_vel.1, BALL_MAX_VERTICAL_SPEED);
+    }
+
+    #[test]
+    fn test_tick_speeds_up_ball_until_max_speed() {
+        let mut state = PongState::new();
+
+        state.ball_vel = (-(BALL_MAX_SPEED - 1), 0);
+        state.ball = (PADDLE_WIDTH + BALL_MAX_SPEED - 2, state.p1);
+        state.tick(None, None);
+        assert_eq!(state.ball_vel.0, BALL_MAX_SPEED);
+
+        state.ball_vel = (BALL_MAX_SPEED, 0);
+        state.ball = (P2_X_POS - BALL_WIDTH, state.p2);
+        state.tick(None, None);
+        assert_eq!(state.ball_vel.0, -BALL_MAX_SPEED);
+    }
+
+    #[test]
+    fn test_tick_serves_at_start_speed_after_a_point() {
+        let mut state = PongState::new();
+
+        state.ball_vel = (-BALL_MAX_SPEED, -1);
+        state.ball.0 = -100;
+        state.tick(None, None);
+
+        assert_eq!(state.ball_vel.0.abs(), BALL_START_VEL.0);
+        assert!(state.ball_vel.1.abs() <= BALL_START_VEL.1);
+    }
+
+    #[test]
+    fn test_conceding_player_serves_towards_the_opponent() {
+        let mut state = PongState::new();
+
+        // p1 concedes
+        state.ball.0 = -100;
+        state.tick(None, None);
+        assert_eq!(state.serving, PlayerOrder::P1);
+        assert!(state.ball_vel.0 > 0);
+
+        // p2 concedes
+        state.ball.0 = GAME_WIDTH + 100;
+        state.tick(None, None);
+        assert_eq!(state.serving, PlayerOrder::P2);
+        assert!(state.ball_vel.0 < 0);
+    }
+
+    #[test]
+    fn test_served_ball_waits_before_moving() {
+        let mut state = PongState::new();
+
+        state.ball.0 = -100;
+        state.tick(None, None);
+
+        let served_at = state.ball;
+        for _ in 0..SERVE_DELAY_TICKS {
+            state.tick(None, None);
+            assert_eq!(state.ball, served_at);
+        }
+
+        state.tick(None, None);
+        assert_ne!(state.ball, served_at);
+    }
+
+    #[test]
+    fn test_serve_angle_follows_the_seed() {
+        let mut a = PongState::new();
+        let mut b = PongState::new();
+
+        // Same seed, same serves.
+        for _ in 0..10 {
+            a.ball.0 = -100;
+            b.ball.0 = -100;
+            a.tick(None, None);
+            b.tick(None, None);
+            assert_eq!(a, b);
+        }
+
+        // A different seed gives different serves at some point.
+        let mut a = PongState::new();
+        let mut c = PongState::new_seeded(POINTS_TO_WIN, 12345);
+        let mut serves = vec![];
+        for _ in 0..10 {
+            a.ball.0 = -100;
+            c.ball.0 = -100;
+            a.tick(None, None);
+            c.tick(None, None);
+            serves.push((a.ball_vel, c.ball_vel));
+        }
+        assert!(serves.iter().any(|(a_vel, c_vel)| a_vel != c_vel));
     }
 
     #[test]
@@ -261,6 +663,54 @@ mod player_tests {
         assert_eq!(state.ball_vel.0, -1);
     }
 
+    #[test]
+    fn test_same_seed_and_inputs_give_identical_states() {
+        let moves = [
+            Some(PlayerMovement::Up),
+            Some(PlayerMovement::Down),
+            Some(PlayerMovement::Still),
+            None,
+        ];
+
+        let mut a = PongState::new_seeded(POINTS_TO_WIN, 99);
+        let mut b = PongState::new_seeded(POINTS_TO_WIN, 99);
+
+        // Long enough for a bunch of points to be played.
+        for i in 0..10_000 {
+            let p1_move = moves[(i / 7) % moves.len()];
+            let p2_move = moves[(i / 11) % moves.len()];
+            a.tick(p1_move, p2_move);
+            b.tick(p1_move, p2_move);
+        }
+
+        assert!(a.p1_score + a.p2_score > 0);
+        assert_eq!(bincode::serialize(&a).unwrap(), bincode::serialize(&b).unwrap());
+    }
+
+    #[test]
+    fn test_rng_survives_serialization() {
+        let mut state = PongState::new_seeded(POINTS_TO_WIN, 1234);
+        let bytes = bincode::serialize(&state).unwrap();
+        let mut copy: PongState = bincode::deserialize(&bytes).unwrap();
+
+        state.ball.0 = -100;
+        copy.ball.0 = -100;
+        state.tick(None, None);
+        copy.tick(None, None);
+
+        assert_eq!(state, copy);
+    }
+
+    #[test]
+    fn test_tick_counts_ticks() {
+        let mut state = PongState::new();
+
+        for _ in 0..3 {
+            state.tick(None, None);
+        }
+        assert_eq!(state.ticks, 3);
+    }
+
     #[test]
     fn test_updates_player_movement() {
         let mut state = PongState::new();
@@ -0,0 +1,161 @@
+// Client side prediction of our own paddle.
+//
+// The client moves its own paddle right away instead of waiting for the
+// server. Every predicted tick is remembered until the server says it has
+// applied it. When a state arrives from the server, we start over from it and
+// replay the ticks the server hasn't seen yet, so our paddle doesn't snap back
+// to where the server (a bit behind) thinks it is.
+
+use crate::game_constants::MAX_PREDICTED_TICKS;
+use crate::net_messages::{InputAck, PlayerOrder};
+use crate::pong_state::{PongState, PlayerMovement};
+use std::collections::VecDeque;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct PredictedTick {
+    // The movement change we had sent during this tick
+    seq: u32,
+    movement: PlayerMovement,
+}
+
+#[derive(Debug)]
+pub struct Prediction {
+    player: PlayerOrder,
+
+    // Ticks not yet acknowledged by the server, oldest first.
+    pending: VecDeque<PredictedTick>,
+}
+
+impl Prediction {
+    pub fn new(player: PlayerOrder) -> Self {
+        Self { player, pending: VecDeque::new() }
+    }
+
+    // Moves the state one tick ahead with our own movement, remembering it
+    // until the server has caught up. `seq` is the latest movement change we
+    // have sent.
+    pub fn predict(&mut self, state: &mut PongState, seq: u32, movement: PlayerMovement) {
+        Self::apply(self.player, state, movement);
+
+        // Don't grow forever if the server stops answering.
+        if self.pending.len() >= MAX_PREDICTED_TICKS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(PredictedTick { seq, movement });
+    }
+
+    // Rewinds to the state from the server and replays the ticks it hasn't
+    // applied yet.
+    pub fn reconcile(&mut self, server_state: PongState, ack: InputAck) -> PongState {
+        self.forget_acknowledged(ack);
+
+        let mut state = server_state;
+        for tick in self.pending.iter() {
+            Self::apply(self.player, &mut state, tick.movement);
+        }
+        state
+    }
+
+    pub fn pending_ticks(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn forget_acknowledged(&mut self, ack: InputAck) {
+        // Everything before the acknowledged change has been applied.
+        while let Some(tick) = self.pending.front() {
+            if tick.seq >= ack.seq {
+                break;
+            }
+            self.pending.pop_front();
+        }
+
+        // The acknowledged change has been applied for this many ticks.
+        for _ in 0..ack.ticks {
+            match self.pending.front() {
+                Some(tick) if tick.seq == ack.seq => {
+                    self.pending.pop_front();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn apply(player: PlayerOrder, state: &mut PongState, movement: PlayerMovement) {
+        match player {
+            PlayerOrder::P1 => state.extrapolate_p1(movement),
+            PlayerOrder::P2 => state.extrapolate_p2(movement),
+        }
+    }
+}
+
+#[cfg(test)]
+mod prediction_tests {
+    use super::*;
+
+    #[test]
+    fn test_reconcile_without_pending_ticks_gives_server_state() {
+        let mut prediction = Prediction::new(PlayerOrder::P1);
+        let mut local = PongState::new();
+
+        for _ in 0..5 {
+            prediction.predict(&mut local, 1, PlayerMovement::Down);
+        }
+
+        let mut server = PongState::new();
+        for _ in 0..5 {
+            server.tick(Some(PlayerMovement::Down), None);
+        }
+
+        let state = prediction.reconcile(server, InputAck { seq: 1, ticks: 5 });
+        assert_eq!(state, server);
+        assert_eq!(prediction.pending_ticks(), 0);
+    }
+
+    #[test]
+    fn test_reconcile_replays_ticks_the_server_has_not_seen() {
+        let mut prediction = Prediction::new(PlayerOrder::P2);
+        let mut local = PongState::new();
+
+        for _ in 0..10 {
+            prediction.predict(&mut local, 1, PlayerMovement::Down);
+        }
+
+        // The server is 6 ticks behind us.
+        let mut server = PongState::new();
+        for _ in 0..4 {
+            server.tick(None, Some(PlayerMovement::Down));
+        }
+
+        let state = prediction.reconcile(server, InputAck { seq: 1, ticks: 4 });
+        assert_eq!(state.p2, local.p2);
+        assert_eq!(prediction.pending_ticks(), 6);
+    }
+
+    #[test]
+    fn test_reconcile_forgets_older_movement_changes() {
+        let mut prediction = Prediction::new(PlayerOrder::P1);
+        let mut local = PongState::new();
+
+        for _ in 0..3 {
+            prediction.predict(&mut local, 1, PlayerMovement::Down);
+        }
+        for _ in 0..3 {
+            prediction.predict(&mut local, 2, PlayerMovement::Up);
+        }
+
+        prediction.reconcile(PongState::new(), InputAck { seq: 2, ticks: 1 });
+        assert_eq!(prediction.pending_ticks(), 2);
+    }
+
+    #[test]
+    fn test_pending_ticks_are_capped() {
+        let mut prediction = Prediction::new(PlayerOrder::P1);
+        let mut local = PongState::new();
+
+        for _ in 0..(MAX_PREDICTED_TICKS + 10) {
+            prediction.predict(&mut local, 1, PlayerMovement::Still);
+        }
+
+        assert_eq!(prediction.pending_ticks(), MAX_PREDICTED_TICKS);
+    }
+}
@@ -0,0 +1,72 @@
+// A small seeded random number generator (splitmix64) for the simulation. It
+// lives inside PongState and is serialized with it, so the same seed and the
+// same inputs always give exactly the same game on every machine.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    // Any seed works, including 0.
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    // Rng::new(rng.state()) continues exactly where rng is.
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // A number between low and high, both included.
+    pub fn range(&mut self, low: i32, high: i32) -> i32 {
+        let span = (high as i64 - low as i64 + 1) as u64;
+        (low as i64 + (self.next_u64() % span) as i64) as i32
+    }
+}
+
+#[cfg(test)]
+mod rng_tests {
+    use super::*;
+
+    #[test]
+    fn test_same_seed_gives_same_numbers() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+
+        for _ in 0..100 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+    }
+
+    #[test]
+    fn test_different_seeds_give_different_numbers() {
+        let mut a = Rng::new(0);
+        let mut b = Rng::new(1);
+
+        assert_ne!(a.next_u64(), b.next_u64());
+    }
+
+    #[test]
+    fn test_range_stays_within_bounds() {
+        let mut rng = Rng::new(7);
+
+        for _ in 0..1000 {
+            let n = rng.range(-3, 3);
+            assert!((-3..=3).contains(&n));
+        }
+
+        assert_eq!(rng.range(5, 5), 5);
+    }
+}
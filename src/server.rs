@@ -1,39 +1,319 @@
+use crate::link_conditioner::{ConditionedTransport, LinkConditions};
+use crate::matchmaking::{Pairing, Queue};
+use crate::net_messages::{ClientMsg, ServerMsg};
 use crate::server_network::ServerNet;
-use crate::server_state::{ServerState, WaitingForP1};
-use laminar::{ErrorKind};
+use crate::server_state::{ServerState, StateUpdate, Running};
+use crate::transport::{Transport, LaminarTransport};
+use crate::error::{Result, parse_addr};
+use crate::game_constants::MS_PER_UPDATE;
 use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
 
-pub fn run(my_addr: &str) ->Result<(), ErrorKind> {
+pub fn run(my_addr: &str, conditions: LinkConditions, pairing: Pairing) -> Result<()> {
 
-    let addr: SocketAddr = my_addr.parse().unwrap();
-    let net = ServerNet::bind(addr)?;
-    let initial_state = Box::new(WaitingForP1::new());
-
-    let mut server = Server::new(net, initial_state);
+    let addr = parse_addr(my_addr)?;
+    let mut transport: Box<dyn Transport> = Box::new(LaminarTransport::bind(addr)?);
+    if !conditions.is_perfect() {
+        println!("Simulating network conditions: {:?}", conditions);
+        transport = Box::new(ConditionedTransport::new(transport, conditions));
+    }
 
-    println!("State: {:?}", server.state);
+    let net = ServerNet::new(transport);
+    let mut server = Server::new(net, pairing);
 
     loop {
-        server.update()?;
+        server.update();
+        thread::sleep(Duration::from_millis(MS_PER_UPDATE));
+    }
+}
+
+// One match, with its own state machine.
+struct Match {
+    id: u32,
+    state: Box<dyn ServerState>,
+}
+
+impl Match {
+    // False when the match can't go on, because we couldn't send to someone
+    // in it. Only that match ends, the others keep going.
+    fn change_state(&mut self, update: StateUpdate) -> bool {
+        match update {
+            Ok(Some(new_state)) => {
+                println!("Match {}: {:?}", self.id, &new_state);
+                self.state = new_state;
+                true
+            }
+            Ok(None) => true,
+            Err(err) => {
+                println!("Match {}: ending it, {}", self.id, err);
+                false
+            }
+        }
     }
 }
 
 struct Server {
     net: ServerNet,
-    state: Box<dyn ServerState>,
+
+    // Players that aren't in a match.
+    queue: Queue,
+
+    matches: Vec<Match>,
+    next_match_id: u32,
 }
 
 impl Server {
-    fn new(net: ServerNet, initial_state: Box<dyn ServerState>) -> Self {
-        Self { net, state: initial_state }
+    fn new(net: ServerNet, pairing: Pairing) -> Self {
+        Self { net, queue: Queue::new(pairing), matches: vec![], next_match_id: 0 }
+    }
+
+    fn new_match(&mut self, p1: SocketAddr, p2: SocketAddr) {
+        let id = self.next_match_id;
+        self.next_match_id += 1;
+
+        let state = Running::new(p1, p2);
+        println!("Match {}: {:?}", id, state);
+        self.matches.push(Match { id, state: Box::new(state) });
+    }
+
+    // Hands every message to the match it's for, pairs up waiting players,
+    // then lets every match move on by one update.
+    fn update(&mut self) {
+        while let Some((addr, msg)) = self.net.poll() {
+            self.route(addr, msg);
+        }
+
+        let net = &self.net;
+        for (p1, p2) in self.queue.pair(|addr| net.rtt_ms(addr), Instant::now()) {
+            self.new_match(p1, p2);
+        }
+        self.queue.update(&mut self.net);
+
+        let net = &mut self.net;
+        self.matches.retain_mut(|game| {
+            let update = game.state.update(net);
+            game.change_state(update)
+        });
+
+        self.forget_empty_matches();
+    }
+
+    fn route(&mut self, addr: SocketAddr, msg: ClientMsg) {
+        if let Some(index) = self.matches.iter().position(|game| game.state.wants(addr, &msg)) {
+            let game = &mut self.matches[index];
+            let update = game.state.on_msg(&mut self.net, addr, msg);
+            if !game.change_state(update) {
+                self.matches.remove(index);
+            }
+            return;
+        }
+
+        if let Err(err) = self.answer(addr, msg) {
+            println!("Could not answer {}: {}", addr, err);
+        }
     }
 
-    // Runs the server within one state until the server changes state.
-    fn update(&mut self) -> Result<(), ErrorKind> {
-        if let Some(new_state) = self.state.update(&mut self.net)? {
-            println!("State: {:?}", &new_state);
-            self.state = new_state;
+    // Messages from those that aren't in a match.
+    fn answer(&mut self, addr: SocketAddr, msg: ClientMsg) -> Result<()> {
+        match msg {
+            ClientMsg::Hi(_) => self.queue.join(addr),
+            ClientMsg::Spectate(_) => self.spectate(addr)?,
+            ClientMsg::Timeout | ClientMsg::Disconnect => self.queue.leave(addr),
+
+            // There is no match to get back into, the client will have to say
+            // hi instead.
+            ClientMsg::Resume(..) => self.net.send(addr, ServerMsg::Abort)?,
+            _ => {}
         }
         Ok(())
     }
+
+    // Spectators watch the match that has been going on the longest. When
+    // there's nothing to watch they're told so, and can ask again later.
+    fn spectate(&mut self, addr: SocketAddr) -> Result<()> {
+        self.queue.leave(addr);
+
+        for game in &mut self.matches {
+            if game.state.add_spectator(&mut self.net, addr)? {
+                return Ok(());
+            }
+        }
+        self.net.send(addr, ServerMsg::Abort)
+    }
+
+    fn forget_empty_matches(&mut self) {
+        let before = self.matches.len();
+        self.matches.retain(|game| !game.state.is_empty());
+
+        if self.matches.len() < before {
+            println!("Matches: {}, queued: {}", self.matches.len(), self.queue.len());
+
+            let dropped = self.net.dropped();
+            if dropped.total() > 0 {
+                println!("Dropped packets so far: {:?}", dropped);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod server_tests {
+    use crate::net_messages::{Hello, PlayerOrder, SessionToken};
+    use crate::test_helper::*;
+    use crate::transport::{MemoryNetwork, MemoryTransport, TransportEvent};
+    use super::*;
+
+    fn starts(client: &mut MemoryTransport) -> Vec<PlayerOrder> {
+        received_server_msgs(client)
+            .into_iter()
+            .filter_map(|msg| match msg {
+                ServerMsg::Start(order, _) => Some(order),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_server_hosts_a_match_per_pair_of_players() {
+        let network = MemoryNetwork::new();
+        let mut server = Server::new(memory_server_net(&network), Pairing::FirstCome);
+        let mut clients: Vec<MemoryTransport> = (0..5)
+            .map(|i| network.bind(addr(&format!("127.0.0.1:{}", 45456 + i))))
+            .collect();
+
+        for client in &mut clients {
+            send_client_msg(client, server.net.addr, ClientMsg::Hi(Hello::new()));
+        }
+        server.update();
+
+        assert_eq!(server.matches.len(), 2);
+        assert_eq!(starts(&mut clients[0]), vec![PlayerOrder::P1]);
+        assert_eq!(starts(&mut clients[1]), vec![PlayerOrder::P2]);
+        assert_eq!(starts(&mut clients[2]), vec![PlayerOrder::P1]);
+        assert_eq!(starts(&mut clients[3]), vec![PlayerOrder::P2]);
+
+        // The odd one out waits for the next player.
+        assert!(received_server_msgs(&mut clients[4]).contains(&ServerMsg::Queued(1)));
+    }
+
+    #[test]
+    fn test_server_only_ends_the_match_it_could_not_send_to() {
+        let network = MemoryNetwork::new();
+        let unreachable = addr("127.0.0.1:45456");
+        let net = unreachable_server_net(&network, unreachable);
+        let mut server = Server::new(net, Pairing::FirstCome);
+        let mut clients: Vec<MemoryTransport> = (0..4)
+            .map(|i| network.bind(addr(&format!("127.0.0.1:{}", 45456 + i))))
+            .collect();
+
+        for client in &mut clients {
+            send_client_msg(client, server.net.addr, ClientMsg::Hi(Hello::new()));
+        }
+        server.update();
+
+        assert_eq!(server.matches.len(), 1);
+        assert_eq!(starts(&mut clients[2]), vec![PlayerOrder::P1]);
+        assert_eq!(starts(&mut clients[3]), vec![PlayerOrder::P2]);
+    }
+
+    #[test]
+    fn test_server_pairs_by_ping_once_players_are_measured() {
+        let network = MemoryNetwork::new();
+        let mut server = Server::new(memory_server_net(&network), Pairing::ClosestPing);
+        let mut clients: Vec<MemoryTransport> = (0..3)
+            .map(|i| network.bind(addr(&format!("127.0.0.1:{}", 45456 + i))))
+            .collect();
+
+        for client in &mut clients {
+            send_client_msg(client, server.net.addr, ClientMsg::Hi(Hello::new()));
+        }
+        server.update();
+        assert!(server.matches.is_empty());
+
+        // Everyone is pinged as soon as they join. The one in the middle
+        // hasn't answered yet, so it's skipped for now.
+        let now = server.net.time_ms();
+        send_client_msg(&mut clients[0], server.net.addr, ClientMsg::Pong(now));
+        send_client_msg(&mut clients[2], server.net.addr, ClientMsg::Pong(now));
+        server.update();
+
+        assert_eq!(server.matches.len(), 1);
+        assert_eq!(starts(&mut clients[0]), vec![PlayerOrder::P1]);
+        assert_eq!(starts(&mut clients[2]), vec![PlayerOrder::P2]);
+        assert!(starts(&mut clients[1]).is_empty());
+    }
+
+    #[test]
+    fn test_server_lets_go_of_matches_that_ended() {
+        let network = MemoryNetwork::new();
+        let mut server = Server::new(memory_server_net(&network), Pairing::FirstCome);
+        let mut p1 = network.bind(addr("127.0.0.1:45456"));
+        let mut p2 = network.bind(addr("127.0.0.1:45457"));
+
+        send_client_msg(&mut p1, server.net.addr, ClientMsg::Hi(Hello::new()));
+        send_client_msg(&mut p2, server.net.addr, ClientMsg::Hi(Hello::new()));
+        server.update();
+        assert_eq!(server.matches.len(), 1);
+
+        // Both gone, the match is over.
+        network.deliver(server.net.addr, TransportEvent::Disconnect(p1.local_addr()));
+        network.deliver(server.net.addr, TransportEvent::Disconnect(p2.local_addr()));
+        server.update();
+        assert!(server.matches.is_empty());
+    }
+
+    #[test]
+    fn test_server_forgets_queued_players_that_leave() {
+        let network = MemoryNetwork::new();
+        let mut server = Server::new(memory_server_net(&network), Pairing::FirstCome);
+        let mut p1 = network.bind(addr("127.0.0.1:45456"));
+
+        send_client_msg(&mut p1, server.net.addr, ClientMsg::Hi(Hello::new()));
+        server.update();
+        assert_eq!(server.queue.len(), 1);
+
+        network.deliver(server.net.addr, TransportEvent::Disconnect(p1.local_addr()));
+        server.update();
+        assert!(server.queue.is_empty());
+        assert!(server.matches.is_empty());
+    }
+
+    #[test]
+    fn test_server_aborts_resumes_without_a_match() {
+        let network = MemoryNetwork::new();
+        let mut server = Server::new(memory_server_net(&network), Pairing::FirstCome);
+        let mut client = network.bind(addr("127.0.0.1:45456"));
+
+        send_client_msg(&mut client, server.net.addr, ClientMsg::Resume(Hello::new(), SessionToken(7)));
+        server.update();
+
+        assert!(received_server_msgs(&mut client).contains(&ServerMsg::Abort));
+        assert!(server.matches.is_empty());
+    }
+
+    #[test]
+    fn test_server_lets_spectators_watch_a_match() {
+        let network = MemoryNetwork::new();
+        let mut server = Server::new(memory_server_net(&network), Pairing::FirstCome);
+        let mut spectator = network.bind(addr("127.0.0.1:45455"));
+        let mut p1 = network.bind(addr("127.0.0.1:45456"));
+        let mut p2 = network.bind(addr("127.0.0.1:45457"));
+
+        // Nothing to watch yet.
+        send_client_msg(&mut spectator, server.net.addr, ClientMsg::Spectate(Hello::new()));
+        server.update();
+        assert!(received_server_msgs(&mut spectator).contains(&ServerMsg::Abort));
+
+        send_client_msg(&mut p1, server.net.addr, ClientMsg::Hi(Hello::with_name("Alice")));
+        send_client_msg(&mut p2, server.net.addr, ClientMsg::Hi(Hello::with_name("Bob")));
+        server.update();
+
+        send_client_msg(&mut spectator, server.net.addr, ClientMsg::Spectate(Hello::new()));
+        server.update();
+
+        let watching = ServerMsg::Watching("Alice".to_string(), "Bob".to_string());
+        assert!(received_server_msgs(&mut spectator).contains(&watching));
+        assert!(server.queue.is_empty());
+    }
 }
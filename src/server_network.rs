@@ -1,46 +1,296 @@
-use bincode::{deserialize, serialize};
-use crate::net_messages::{ClientMsg, ServerMsg};
-use laminar::{Packet, Socket, SocketEvent, ErrorKind};
+use crate::channels::Channel;
+use crate::codec::{encode_server_msg, DecodeError};
+use crate::error::Result;
+use crate::net_messages::{
+    ClientMsg,
+    ServerMsg,
+    ClockSync,
+    Capabilities,
+    Hello,
+    RejectReason,
+    PROTOCOL_VERSION,
+    clean_name,
+};
+use crate::packet_filter::{PacketFilter, Dropped, DropCounts};
+use crate::transport::{Transport, TransportEvent, LaminarTransport};
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::time::{Instant};
 
 pub struct ServerNet {
     pub addr: SocketAddr,
-    socket: Socket,
+    transport: Box<dyn Transport>,
+
+    // The server clock starts when the transport is set up.
+    started: Instant,
+
+    // Latest measured round trip time to each client, in ms.
+    rtts: HashMap<SocketAddr, u64>,
+
+    // What each accepted client and the server can both do.
+    capabilities: HashMap<SocketAddr, Capabilities>,
+
+    // What the clients that said what they're called are called.
+    names: HashMap<SocketAddr, String>,
+
+    // Keeps bad packets from reaching the game.
+    filter: PacketFilter,
 }
 
 impl ServerNet {
-    pub fn bind(addr: SocketAddr) -> Result<ServerNet, ErrorKind> {
-        let socket = Socket::bind(addr)?;
-        Ok(Self { addr, socket })
+    pub fn bind(addr: SocketAddr) -> Result<ServerNet> {
+        Ok(Self::new(Box::new(LaminarTransport::bind(addr)?)))
+    }
+
+    pub fn new(transport: Box<dyn Transport>) -> Self {
+        Self {
+            addr: transport.local_addr(),
+            transport,
+            started: Instant::now(),
+            rtts: HashMap::new(),
+            capabilities: HashMap::new(),
+            names: HashMap::new(),
+            filter: PacketFilter::new(),
+        }
+    }
+
+    // Milliseconds since the server started.
+    pub fn time_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    pub fn rtt_ms(&self, addr: SocketAddr) -> Option<u64> {
+        self.rtts.get(&addr).copied()
+    }
+
+    // Clients that haven't been accepted get nothing optional.
+    pub fn capabilities(&self, addr: SocketAddr) -> Capabilities {
+        self.capabilities.get(&addr).copied().unwrap_or_default()
+    }
+
+    pub fn name(&self, addr: SocketAddr) -> Option<&str> {
+        self.names.get(&addr).map(String::as_str)
+    }
+
+    pub fn dropped(&self) -> DropCounts {
+        self.filter.dropped()
+    }
+
+    // Pings a client, telling it about the server clock and the current tick.
+    pub fn ping(&mut self, dst: SocketAddr, tick: u32) -> Result<()> {
+        let clock = ClockSync { tick, time_ms: self.time_ms(), rtt_ms: self.rtt_ms(dst) };
+        self.send(dst, ServerMsg::Ping(clock))
     }
 
     pub fn poll(&mut self) -> Option<(SocketAddr, ClientMsg)> {
         // The server doesn't run network communication in another thread. It
         // probably should though.
 
-        self.socket.manual_poll(Instant::now());
+        // Packets we can't or won't pass on are skipped.
+        loop {
+            let event = self.transport.recv()?;
+            let (addr, msg) = match event {
+                TransportEvent::Packet(addr, payload, _) => {
+                    let msg = match self.filter.check(addr, &payload, Instant::now()) {
+                        Ok(msg) => msg,
+                        Err(Dropped::Malformed(DecodeError::WrongVersion(_))) => {
+                            self.reject(addr, RejectReason::UnsupportedVersion {
+                                server_version: PROTOCOL_VERSION,
+                            });
+                            continue;
+                        }
+                        Err(_) => continue,
+                    };
+
+                    // A pong tells us how long the ping took to go back and
+                    // forth.
+                    if let ClientMsg::Pong(sent_ms) = msg {
+                        let rtt = self.time_ms().saturating_sub(sent_ms);
+                        self.rtts.insert(addr, rtt);
+                    }
+
+                    // Clients we can't play with never get past saying hi.
+                    let accepted = match &msg {
+                        ClientMsg::Hi(hello)
+                        | ClientMsg::Resume(hello, _)
+                        | ClientMsg::Spectate(hello) => self.handshake(addr, hello),
+                        _ => true,
+                    };
+                    if !accepted {
+                        continue;
+                    }
+
+                    (addr, msg)
+                }
+                TransportEvent::Timeout(addr) => {
+                    self.forget(addr);
+                    (addr, ClientMsg::Timeout)
+                }
+                TransportEvent::Connect(addr) => (addr, ClientMsg::Connect),
+                TransportEvent::Disconnect(addr) => {
+                    self.forget(addr);
+                    (addr, ClientMsg::Disconnect)
+                }
+            };
+            return Some((addr, msg));
+        }
+    }
+
+    // Accepts or rejects a client saying hi. Returns whether it was accepted.
+    fn handshake(&mut self, addr: SocketAddr, hello: &Hello) -> bool {
+        if hello.version != PROTOCOL_VERSION {
+            self.reject(addr, RejectReason::UnsupportedVersion { server_version: PROTOCOL_VERSION });
+            return false;
+        }
+
+        let shared = Capabilities::all().shared_with(hello.capabilities);
+        self.capabilities.insert(addr, shared);
+        // Clients can send anything, it's cleaned up before anyone sees it.
+        let name = clean_name(&hello.name);
+        if !name.is_empty() {
+            self.names.insert(addr, name);
+        }
+        if self.send(addr, ServerMsg::Accept(shared)).is_err() {
+            println!("Could not accept {}", addr);
+        }
+        true
+    }
+
+    // Whoever talks from the address next has to say hi again, and gets
+    // nothing of what we knew about the one before.
+    fn forget(&mut self, addr: SocketAddr) {
+        self.rtts.remove(&addr);
+        self.capabilities.remove(&addr);
+        self.names.remove(&addr);
+    }
 
-        let pkt = self.socket.recv()?;
-        let (addr, msg) = match pkt {
-            SocketEvent::Packet(pkt) => {
-                let msg = deserialize::<ClientMsg>(pkt.payload()).unwrap();
-                (pkt.addr(), msg)
+    fn reject(&mut self, addr: SocketAddr, reason: RejectReason) {
+        println!("Rejected {}: {:?}", addr, reason);
+        if self.send(addr, ServerMsg::Reject(reason)).is_err() {
+            println!("Could not reject {}", addr);
+        }
+    }
+
+    pub fn send(&mut self, dst: SocketAddr, msg: ServerMsg) -> Result<()> {
+        let channel = Channel::for_server_msg(&msg);
+        self.transport.send(dst, encode_server_msg(&msg), channel)
+    }
+}
+
+#[cfg(test)]
+mod server_network_tests {
+    use crate::codec::encode_client_msg;
+    use crate::test_helper::*;
+    use crate::transport::MemoryNetwork;
+    use laminar::{Packet, Socket};
+    use std::net::UdpSocket;
+    use super::*;
+
+    #[test]
+    fn test_pong_measures_round_trip_time() {
+        let network = MemoryNetwork::new();
+        let mut net = memory_server_net(&network);
+        let mut client = network.bind(addr("127.0.0.1:45456"));
+        let sent_ms = net.time_ms();
+
+        send_client_msg(&mut client, net.addr, ClientMsg::Pong(sent_ms));
+
+        assert_eq!(net.poll(), Some((client.local_addr(), ClientMsg::Pong(sent_ms))));
+        assert!(net.rtt_ms(client.local_addr()).is_some());
+    }
+
+    #[test]
+    fn test_garbage_is_dropped_without_crashing() {
+        let mut net = working_server_net();
+        let mut client = Socket::bind("127.0.0.1:0").unwrap();
+
+        // Not even a laminar packet.
+        let raw = UdpSocket::bind("127.0.0.1:0").unwrap();
+        raw.send_to(&[0xff; 32], net.addr).unwrap();
+
+        let garbage = vec![PROTOCOL_VERSION, 0xe0, 1, 2, 3];
+        let ack = encode_client_msg(&ClientMsg::AckState(3));
+        client.send(Packet::unreliable(net.addr, garbage)).unwrap();
+        client.send(Packet::unreliable(net.addr, ack)).unwrap();
+        client.manual_poll(Instant::now());
+
+        let mut acked = None;
+        for _ in 0..100 {
+            if let Some((_, ClientMsg::AckState(tick))) = net.poll() {
+                acked = Some(tick);
+                break;
             }
-            SocketEvent::Timeout(addr) => (addr, ClientMsg::Timeout),
-            SocketEvent::Connect(addr) => (addr, ClientMsg::Connect),
-            SocketEvent::Disconnect(addr) => (addr, ClientMsg::Disconnect),
-        };
-        Some((addr, msg))
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(acked, Some(3), "The ack never arrived");
+        assert_eq!(net.dropped().malformed, 1);
     }
 
-    pub fn send(&mut self, dst: SocketAddr, msg: ServerMsg) -> Result<(), ErrorKind> {
-        let msg = serialize(&msg).unwrap();
-        let packet = Packet::reliable_ordered(dst, msg, None);
+    #[test]
+    fn test_hi_is_accepted_with_shared_capabilities() {
+        let network = MemoryNetwork::new();
+        let mut net = memory_server_net(&network);
+        let mut client = network.bind(addr("127.0.0.1:45456"));
+
+        let hello = Hello { capabilities: Capabilities::default(), ..Hello::with_name("Alice") };
+        send_client_msg(&mut client, net.addr, ClientMsg::Hi(hello.clone()));
+
+        assert_eq!(net.poll(), Some((client.local_addr(), ClientMsg::Hi(hello))));
+        assert_eq!(net.capabilities(client.local_addr()), Capabilities::default());
+        assert_eq!(net.name(client.local_addr()), Some("Alice"));
+        assert_eq!(
+            received_server_msgs(&mut client),
+            vec![ServerMsg::Accept(Capabilities::default())]
+        );
+    }
+
+    #[test]
+    fn test_names_are_cleaned_up_by_the_server() {
+        let network = MemoryNetwork::new();
+        let mut net = memory_server_net(&network);
+        let mut client = network.bind(addr("127.0.0.1:45456"));
+
+        let hello = Hello { name: "Mallory\u{7}\u{7} has a long name".to_string(), ..Hello::new() };
+        send_client_msg(&mut client, net.addr, ClientMsg::Hi(hello));
+        net.poll();
+
+        assert_eq!(net.name(client.local_addr()), Some("Mallory has a lo"));
+    }
+
+    #[test]
+    fn test_clients_that_leave_are_forgotten() {
+        let network = MemoryNetwork::new();
+        let mut net = memory_server_net(&network);
+        let mut client = network.bind(addr("127.0.0.1:45456"));
+        let client_addr = client.local_addr();
+
+        send_client_msg(&mut client, net.addr, ClientMsg::Hi(Hello::with_name("Alice")));
+        send_client_msg(&mut client, net.addr, ClientMsg::Pong(net.time_ms()));
+        net.poll();
+        net.poll();
+        assert_eq!(net.name(client_addr), Some("Alice"));
+
+        network.deliver(net.addr, TransportEvent::Timeout(client_addr));
+        assert_eq!(net.poll(), Some((client_addr, ClientMsg::Timeout)));
+
+        assert_eq!(net.name(client_addr), None);
+        assert_eq!(net.rtt_ms(client_addr), None);
+        assert_eq!(net.capabilities(client_addr), Capabilities::default());
+    }
+
+    #[test]
+    fn test_hi_from_other_version_is_rejected() {
+        let network = MemoryNetwork::new();
+        let mut net = memory_server_net(&network);
+        let mut client = network.bind(addr("127.0.0.1:45456"));
+
+        let hello = Hello { version: PROTOCOL_VERSION + 1, ..Hello::new() };
+        send_client_msg(&mut client, net.addr, ClientMsg::Hi(hello));
 
-        self.socket.send(packet)?;
-        self.socket.manual_poll(Instant::now());
+        // The server keeps the rejected hi to itself.
+        assert_eq!(net.poll(), None);
 
-        Ok(())
+        let reason = RejectReason::UnsupportedVersion { server_version: PROTOCOL_VERSION };
+        assert_eq!(received_server_msgs(&mut client), vec![ServerMsg::Reject(reason)]);
     }
 }
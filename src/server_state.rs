@@ -1,33 +1,44 @@
+mod ended;
+mod paused;
 mod running;
-mod waiting_for_p1;
-mod waiting_for_p2;
 
+pub use ended::Ended;
+pub use paused::Paused;
 pub use running::Running;
-pub use waiting_for_p1::WaitingForP1;
-pub use waiting_for_p2::WaitingForP2;
+use crate::net_messages::ClientMsg;
 use crate::server_network::ServerNet;
-use laminar::ErrorKind;
-use std::thread;
-use std::time::{Duration};
+use crate::error::Error;
+use std::net::SocketAddr;
 
-pub type StateUpdate = Result<Option<Box<dyn ServerState>>, ErrorKind>;
+pub type StateUpdate = Result<Option<Box<dyn ServerState>>, Error>;
 
 pub fn new_state(state: Box<dyn ServerState>) -> StateUpdate {
     Ok(Some(state))
 }
 
+// The state of one match. The server can host many matches at once, it hands
+// every state the messages meant for it and updates them all in turn. Players
+// waiting for a match are kept in the matchmaking queue rather than here.
 pub trait ServerState: std::fmt::Debug {
 
-    // Runs until there is a new state to return
-    fn update(&mut self, net: &mut ServerNet) -> StateUpdate;
+    // Is the message for this match? For example because it's from one of its
+    // players.
+    fn wants(&self, addr: SocketAddr, msg: &ClientMsg) -> bool;
+
+    // Handles a message this state wants.
+    fn on_msg(&mut self, net: &mut ServerNet, addr: SocketAddr, msg: ClientMsg) -> StateUpdate;
 
-    // How long can we wait between loop iterations in a state? For example,
-    // while waiting for player 2, we don't need to spam player 1 with info.
-    fn sleep_time_ms(&self) -> u64;
+    // Called once every server update, after the messages have been handled.
+    fn update(&mut self, net: &mut ServerNet) -> StateUpdate;
 
-    fn sleep(&mut self) {
-        let duration = Duration::from_millis(self.sleep_time_ms());
-        thread::sleep(duration);
+    // Lets someone watch the match. Returns whether there was anything to
+    // watch.
+    fn add_spectator(&mut self, _net: &mut ServerNet, _addr: SocketAddr) -> Result<bool, Error> {
+        Ok(false)
     }
 
+    // A match without any players in it can be let go of.
+    fn is_empty(&self) -> bool {
+        false
+    }
 }
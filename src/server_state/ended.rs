@@ -0,0 +1,40 @@
+use core::fmt::Debug;
+use crate::net_messages::{ClientMsg};
+use crate::server_network::ServerNet;
+use std::net::SocketAddr;
+use super::{ServerState, StateUpdate};
+
+// What a match turns into once it's over, one way or another. Its players are
+// free to queue up for another one.
+#[derive(Debug)]
+pub struct Ended { }
+
+impl Ended {
+    pub fn new() -> Self {
+        Self { }
+    }
+}
+
+impl Default for Ended {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerState for Ended {
+    fn wants(&self, _addr: SocketAddr, _msg: &ClientMsg) -> bool {
+        false
+    }
+
+    fn on_msg(&mut self, _net: &mut ServerNet, _addr: SocketAddr, _msg: ClientMsg) -> StateUpdate {
+        Ok(None)
+    }
+
+    fn update(&mut self, _net: &mut ServerNet) -> StateUpdate {
+        Ok(None)
+    }
+
+    fn is_empty(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,180 @@
+use core::fmt::Debug;
+use crate::net_messages::{ClientMsg, ServerMsg, PlayerOrder};
+use crate::server_network::ServerNet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use super::running::Running;
+use super::ended::Ended;
+use super::{ServerState, new_state, StateUpdate};
+
+use crate::game_constants::RECONNECT_GRACE_MS;
+
+// A match where one of the players has lost contact. Nothing moves until they
+// resume the match or the grace period is over.
+#[derive(Debug)]
+pub struct Paused {
+    running: Running,
+
+    // The player we're waiting for, and since when.
+    away: PlayerOrder,
+    since: Instant,
+
+    // Keeps track of when its time to remind the other player.
+    last_countdown: Option<Instant>,
+}
+
+impl Paused {
+    pub fn new(running: Running, away: PlayerOrder) -> Self {
+        println!("{:?} lost contact, pausing the match", away);
+
+        Self { running, away, since: Instant::now(), last_countdown: None }
+    }
+
+    fn time_left(&self) -> Duration {
+        Duration::from_millis(RECONNECT_GRACE_MS).checked_sub(self.since.elapsed())
+            .unwrap_or_default()
+    }
+
+    // The player that's still here, and the spectators.
+    fn waiting(&self) -> Vec<SocketAddr> {
+        let mut waiting = vec![self.running.player_addr(self.away.opponent())];
+        waiting.extend_from_slice(self.running.spectators());
+        waiting
+    }
+
+    fn abort(&self, net: &mut ServerNet) -> StateUpdate {
+        for addr in self.waiting() {
+            net.send(addr, ServerMsg::Abort)?;
+        }
+        new_state(Box::new(Ended::new()))
+    }
+
+    // Tells everyone still here how long they might have to wait, once a
+    // second. Doubles as a ping, so that they don't time out while waiting.
+    fn maybe_send_countdown(&mut self, net: &mut ServerNet) -> crate::error::Result<()> {
+        if let Some(last_countdown) = self.last_countdown {
+            if last_countdown.elapsed() < Duration::from_secs(1) {
+                return Ok(());
+            }
+        }
+
+        let seconds_left = (self.time_left().as_millis() as u32).div_ceil(1000);
+        for addr in self.waiting() {
+            net.send(addr, ServerMsg::Paused(seconds_left))?;
+            net.ping(addr, self.running.ticks())?;
+        }
+
+        self.last_countdown = Some(Instant::now());
+        Ok(())
+    }
+}
+
+impl ServerState for Paused {
+    fn wants(&self, addr: SocketAddr, msg: &ClientMsg) -> bool {
+        self.running.wants(addr, msg)
+    }
+
+    fn on_msg(&mut self, net: &mut ServerNet, addr: SocketAddr, msg: ClientMsg) -> StateUpdate {
+        let remaining = self.running.player_addr(self.away.opponent());
+
+        match msg {
+            ClientMsg::Resume(_, token) => {
+                // The player that's still here might resume too, from a new
+                // address. Then we keep on waiting.
+                let player = self.running.resume(net, addr, token, Some(self.away))?;
+                if player == Some(self.away) {
+                    return new_state(Box::new(self.running.clone()));
+                }
+            }
+
+            ClientMsg::Spectate(_) => self.running.spectate(net, addr)?,
+
+            ClientMsg::Timeout | ClientMsg::Disconnect if addr == remaining => {
+                // Nobody left to wait with.
+                for &spectator in self.running.spectators() {
+                    net.send(spectator, ServerMsg::Abort)?;
+                }
+                return new_state(Box::new(Ended::new()));
+            }
+            ClientMsg::Timeout | ClientMsg::Disconnect => self.running.forget_spectator(addr),
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    fn update(&mut self, net: &mut ServerNet) -> StateUpdate {
+        if self.time_left() == Duration::from_millis(0) {
+            return self.abort(net);
+        }
+
+        self.maybe_send_countdown(net)?;
+        Ok(None)
+    }
+
+    fn add_spectator(&mut self, net: &mut ServerNet, addr: SocketAddr) -> crate::error::Result<bool> {
+        self.running.add_spectator(net, addr)
+    }
+}
+
+#[cfg(test)]
+mod paused_tests {
+    use crate::net_messages::Hello;
+    use crate::test_helper::*;
+    use crate::transport::{MemoryNetwork, Transport};
+    use super::*;
+
+    fn paused(away: PlayerOrder) -> Paused {
+        let running = Running::new(addr("127.0.0.1:45456"), addr("127.0.0.1:45457"));
+        Paused::new(running, away)
+    }
+
+    #[test]
+    fn test_paused_transitions_to_running_when_player_resumes() {
+        let network = MemoryNetwork::new();
+        let mut net = memory_server_net(&network);
+        let mut moved = network.bind(addr("127.0.0.1:45458"));
+        let mut state = paused(PlayerOrder::P1);
+        let token = state.running.session_token(PlayerOrder::P1);
+
+        let resume = ClientMsg::Resume(Hello::new(), token);
+        assert!(state.wants(moved.local_addr(), &resume));
+
+        assert_state_update(state.on_msg(&mut net, moved.local_addr(), resume), "Running");
+        assert_eq!(received_server_msgs(&mut moved), vec![ServerMsg::Start(PlayerOrder::P1, token)]);
+    }
+
+    #[test]
+    fn test_paused_counts_down_for_the_remaining_player() {
+        let network = MemoryNetwork::new();
+        let mut net = memory_server_net(&network);
+        let mut p2 = network.bind(addr("127.0.0.1:45457"));
+        let mut state = paused(PlayerOrder::P1);
+
+        state.update(&mut net).unwrap();
+
+        let seconds_left = (RECONNECT_GRACE_MS / 1000) as u32;
+        assert_eq!(received_server_msgs(&mut p2)[0], ServerMsg::Paused(seconds_left));
+    }
+
+    #[test]
+    fn test_paused_gives_up_when_the_remaining_player_leaves() {
+        let network = MemoryNetwork::new();
+        let mut net = memory_server_net(&network);
+        let mut state = paused(PlayerOrder::P1);
+
+        let update = state.on_msg(&mut net, addr("127.0.0.1:45457"), ClientMsg::Disconnect);
+        assert_state_update(update, "Ended");
+    }
+
+    #[test]
+    fn test_paused_aborts_after_grace_period() {
+        let network = MemoryNetwork::new();
+        let mut net = memory_server_net(&network);
+        let mut p1 = network.bind(addr("127.0.0.1:45456"));
+        let mut state = paused(PlayerOrder::P2);
+        state.since = Instant::now() - Duration::from_millis(RECONNECT_GRACE_MS);
+
+        assert_state_update(state.update(&mut net), "Ended");
+        assert_eq!(received_server_msgs(&mut p1), vec![ServerMsg::Abort]);
+    }
+}
@@ -1,32 +1,89 @@
 use core::fmt::Debug;
-use crate::net_messages::{ClientMsg, ServerMsg, PlayerOrder};
+use crate::net_messages::{
+    ClientMsg, ServerMsg, PlayerOrder, MatchResult, MoveInput, InputAck, SessionToken,
+};
+use crate::delta::{DeltaEncoder, Snapshot};
 use crate::pong_state::{PongState, PlayerMovement};
 use crate::server_network::ServerNet;
-use laminar::ErrorKind;
+use crate::error::Result;
+use crate::rng::Rng;
+use std::collections::VecDeque;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::net::SocketAddr;
-use std::time::{Duration, Instant};
-use super::waiting_for_p1::WaitingForP1;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use super::paused::Paused;
+use super::ended::Ended;
 use super::{ServerState, new_state, StateUpdate};
 
 use crate::game_constants::{
-    MS_PER_UPDATE,
+    MAX_INPUT_LEAD_TICKS,
     MS_PER_PING,
     MS_PER_STATE_BROADCAST,
+    MS_PER_SPECTATOR_BROADCAST,
+    POINTS_TO_WIN,
+    RESUME_AFTER_SILENCE_MS,
 };
 
+// Tokens are all that keeps others out of a player's place, so they can't come
+// from anything that goes out on the wire, like the simulation rng. The hasher
+// keys std seeds from the OS are random enough.
+fn new_session_token() -> SessionToken {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos())
+        .unwrap_or(0);
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(nanos);
+    SessionToken(hasher.finish())
+}
+
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Running {
     // used to communicating with the different players
     p1: SocketAddr,
     p2: SocketAddr,
 
-    // Keeps track of what move a player is doing during one game update. They
-    // will reset between updates so the play will have to keep sending movement
-    // updates.
+    // Lets a player that lost contact take its place again, from any address.
+    p1_token: SessionToken,
+    p2_token: SessionToken,
+
+    // When we last heard from each player. A player can only be taken over by
+    // someone resuming when it has gone quiet.
+    p1_heard: Instant,
+    p2_heard: Instant,
+
+    // Those watching the match. They get the state now and then, but can't
+    // do anything about it.
+    spectators: Vec<SocketAddr>,
+
+    // Whether the players have been told that the match started. A match
+    // that goes on after a pause has.
+    started: bool,
+
+    // Keeps track of what move a player is doing. Clients only tell us when
+    // their movement changes, so it's kept between updates.
     p1_move: Option<PlayerMovement>,
     p2_move: Option<PlayerMovement>,
 
+    // Movement changes that arrived ahead of the tick they're for, oldest
+    // first. Their ticks are clamped to what we're willing to wait for.
+    p1_inputs: VecDeque<MoveInput>,
+    p2_inputs: VecDeque<MoveInput>,
+
+    // The latest movement change applied for each player, and for how many
+    // ticks. Changes with older sequence numbers are duplicates or arrived out
+    // of order.
+    p1_ack: InputAck,
+    p2_ack: InputAck,
+
+    // Keeps track of which states each player has acknowledged, so that we
+    // only need to send what changed since then.
+    p1_encoder: DeltaEncoder,
+    p2_encoder: DeltaEncoder,
+
     pong_state: PongState,
 
     // Keeps track of when its time to ping the clients
@@ -34,92 +91,657 @@ pub struct Running {
 
     // Keeps track of when its time to send pong state to clients
     last_state_broadcast: Instant,
+    last_spectator_broadcast: Instant,
 }
 
 impl Running {
     pub fn new(p1: SocketAddr, p2: SocketAddr) -> Self {
-        let pong_state = PongState::new();
+        // Every match gets its own seed, so serves differ between matches.
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or(0);
+
+        let pong_state = PongState::new_seeded(POINTS_TO_WIN, seed);
+        let p1_token = new_session_token();
+        let p2_token = new_session_token();
+
         let last_ping = Instant::now();
         let last_state_broadcast = Instant::now();
         let p1_move = None;
         let p2_move = None;
 
-        Self { p1, p2, p1_move, p2_move, pong_state, last_ping, last_state_broadcast }
+        Self {
+            p1,
+            p2,
+            p1_token,
+            p2_token,
+            p1_heard: Instant::now(),
+            p2_heard: Instant::now(),
+            spectators: vec![],
+            started: false,
+            p1_move,
+            p2_move,
+            p1_inputs: VecDeque::new(),
+            p2_inputs: VecDeque::new(),
+            p1_ack: InputAck::default(),
+            p2_ack: InputAck::default(),
+            p1_encoder: DeltaEncoder::new(),
+            p2_encoder: DeltaEncoder::new(),
+            pong_state,
+            last_ping,
+            last_state_broadcast,
+            last_spectator_broadcast: Instant::now(),
+        }
+    }
+
+    pub(super) fn player_addr(&self, player: PlayerOrder) -> SocketAddr {
+        match player {
+            PlayerOrder::P1 => self.p1,
+            PlayerOrder::P2 => self.p2,
+        }
+    }
+
+    pub(super) fn spectators(&self) -> &[SocketAddr] {
+        &self.spectators
+    }
+
+    pub(super) fn forget_spectator(&mut self, addr: SocketAddr) {
+        self.spectators.retain(|&spectator| spectator != addr);
+    }
+
+    // Players that didn't say what they're called go by their number.
+    fn player_name(&self, net: &ServerNet, player: PlayerOrder) -> String {
+        match net.name(self.player_addr(player)) {
+            Some(name) => name.to_string(),
+            None => match player {
+                PlayerOrder::P1 => "Player 1".to_string(),
+                PlayerOrder::P2 => "Player 2".to_string(),
+            },
+        }
+    }
+
+    pub(super) fn ticks(&self) -> u32 {
+        self.pong_state.ticks
+    }
+
+    #[cfg(test)]
+    pub(super) fn session_token(&self, player: PlayerOrder) -> SessionToken {
+        match player {
+            PlayerOrder::P1 => self.p1_token,
+            PlayerOrder::P2 => self.p2_token,
+        }
     }
 
-    fn maybe_ping_clients(&mut self, net: &mut ServerNet) -> Result<(), ErrorKind> {
+    pub(super) fn player_at(&self, addr: SocketAddr) -> Option<PlayerOrder> {
+        if addr == self.p1 {
+            Some(PlayerOrder::P1)
+        } else if addr == self.p2 {
+            Some(PlayerOrder::P2)
+        } else {
+            None
+        }
+    }
+
+    fn is_player_token(&self, token: SessionToken) -> bool {
+        token == self.p1_token || token == self.p2_token
+    }
+
+    fn heard_from(&mut self, addr: SocketAddr) {
+        match self.player_at(addr) {
+            Some(PlayerOrder::P1) => self.p1_heard = Instant::now(),
+            Some(PlayerOrder::P2) => self.p2_heard = Instant::now(),
+            None => {}
+        }
+    }
+
+    // Whether a player has been quiet long enough that it has most likely
+    // lost contact, even if the transport hasn't noticed yet.
+    fn is_silent(&self, player: PlayerOrder) -> bool {
+        let heard = match player {
+            PlayerOrder::P1 => self.p1_heard,
+            PlayerOrder::P2 => self.p2_heard,
+        };
+        heard.elapsed() >= Duration::from_millis(RESUME_AFTER_SILENCE_MS)
+    }
+
+    // Puts the player with the token at the address it now talks from, and
+    // tells it where the match is at. Returns the player, if it was one.
+    // Only the player we know is away, or one that has gone quiet, can be
+    // resumed, so that a player still playing can't be pushed out.
+    pub(super) fn resume(
+        &mut self,
+        net: &mut ServerNet,
+        addr: SocketAddr,
+        token: SessionToken,
+        away: Option<PlayerOrder>
+    ) -> Result<Option<PlayerOrder>> {
+        let player = if token == self.p1_token {
+            PlayerOrder::P1
+        } else if token == self.p2_token {
+            PlayerOrder::P2
+        } else {
+            // Not this match, most likely one that's already over.
+            net.send(addr, ServerMsg::Abort)?;
+            return Ok(None);
+        };
+
+        let from_player = self.player_addr(player) == addr;
+        if !from_player && away != Some(player) && !self.is_silent(player) {
+            net.send(addr, ServerMsg::Abort)?;
+            return Ok(None);
+        }
+
+        // The client starts over with its movement changes and the states it
+        // has seen.
+        match player {
+            PlayerOrder::P1 => {
+                self.p1 = addr;
+                self.p1_heard = Instant::now();
+                self.p1_move = None;
+                self.p1_inputs.clear();
+                self.p1_ack = InputAck::default();
+                self.p1_encoder = DeltaEncoder::new();
+            }
+            PlayerOrder::P2 => {
+                self.p2 = addr;
+                self.p2_heard = Instant::now();
+                self.p2_move = None;
+                self.p2_inputs.clear();
+                self.p2_ack = InputAck::default();
+                self.p2_encoder = DeltaEncoder::new();
+            }
+        }
+
+        net.send(addr, ServerMsg::Start(player, token))?;
+        Ok(Some(player))
+    }
+
+    // Players can't watch their own match. They're told so, like spectators
+    // with nothing to watch, and can ask again.
+    pub(super) fn spectate(&mut self, net: &mut ServerNet, addr: SocketAddr) -> Result<()> {
+        if !self.add_spectator(net, addr)? {
+            net.send(addr, ServerMsg::Abort)?;
+        }
+        Ok(())
+    }
+
+    // Queues a movement change from one of the players for the tick it's
+    // for, unless a newer one has already been seen. Changes that are late
+    // are applied on the next tick, we don't rewind the match for them.
+    fn apply_move(&mut self, addr: SocketAddr, input: MoveInput) {
+        let (inputs, player_ack) = if addr == self.p1 {
+            (&mut self.p1_inputs, &self.p1_ack)
+        } else if addr == self.p2 {
+            (&mut self.p2_inputs, &self.p2_ack)
+        } else {
+            return;
+        };
+
+        let newest_seq = inputs.back().map_or(player_ack.seq, |newest| newest.seq);
+        if input.seq <= newest_seq {
+            return;
+        }
+
+        // Changes are applied in the order they were made.
+        let earliest = inputs.back().map_or(0, |newest| newest.tick);
+        let latest = self.pong_state.ticks + MAX_INPUT_LEAD_TICKS;
+        let tick = input.tick.min(latest).max(earliest);
+        inputs.push_back(MoveInput { tick, ..input });
+    }
+
+    // Applies the movement changes that are due before the next tick.
+    fn apply_due_moves(
+        inputs: &mut VecDeque<MoveInput>,
+        player_move: &mut Option<PlayerMovement>,
+        player_ack: &mut InputAck,
+        tick: u32
+    ) {
+        while let Some(input) = inputs.front() {
+            if input.tick > tick {
+                break;
+            }
+            *player_move = Some(input.movement);
+            *player_ack = InputAck { seq: input.seq, ticks: 0 };
+            inputs.pop_front();
+        }
+    }
+
+    fn tick(&mut self) {
+        let tick = self.pong_state.ticks;
+        Self::apply_due_moves(&mut self.p1_inputs, &mut self.p1_move, &mut self.p1_ack, tick);
+        Self::apply_due_moves(&mut self.p2_inputs, &mut self.p2_move, &mut self.p2_ack, tick);
+
+        self.pong_state.tick(self.p1_move, self.p2_move);
+        self.p1_ack.ticks += 1;
+        self.p2_ack.ticks += 1;
+    }
+
+    fn ack_state(&mut self, addr: SocketAddr, tick: u32) {
+        if addr == self.p1 {
+            self.p1_encoder.ack(tick);
+        } else if addr == self.p2 {
+            self.p2_encoder.ack(tick);
+        }
+    }
+
+    // Both players get the same state, but each with their own input ack and
+    // against their own baseline. Clients that can't handle deltas always get
+    // the full state.
+    fn send_state(&mut self, net: &mut ServerNet) -> Result<()> {
+        let p1_snapshot = Self::snapshot(net, self.p1, &mut self.p1_encoder, self.pong_state);
+        let p2_snapshot = Self::snapshot(net, self.p2, &mut self.p2_encoder, self.pong_state);
+        let p1_msg = Self::state_msg(p1_snapshot, self.p1_ack);
+        let p2_msg = Self::state_msg(p2_snapshot, self.p2_ack);
+
+        net.send(self.p1, p1_msg)?;
+        net.send(self.p2, p2_msg)?;
+        Ok(())
+    }
+
+    fn snapshot(
+        net: &ServerNet,
+        addr: SocketAddr,
+        encoder: &mut DeltaEncoder,
+        state: PongState
+    ) -> Snapshot {
+        if net.capabilities(addr).state_deltas {
+            encoder.encode(state)
+        } else {
+            Snapshot::Full(state)
+        }
+    }
+
+    fn state_msg(snapshot: Snapshot, ack: InputAck) -> ServerMsg {
+        match snapshot {
+            Snapshot::Full(state) => ServerMsg::State(state, ack),
+            Snapshot::Delta(delta) => ServerMsg::StateDelta(delta, ack),
+        }
+    }
+
+    fn maybe_ping_clients(&mut self, net: &mut ServerNet) -> Result<()> {
         // Is it time for another ping?
         if Instant::now() - self.last_ping >= Duration::from_millis(MS_PER_PING) {
-            net.send(self.p1, ServerMsg::Ping)?;
-            net.send(self.p2, ServerMsg::Ping)?;
+            net.ping(self.p1, self.pong_state.ticks)?;
+            net.ping(self.p2, self.pong_state.ticks)?;
+            for &spectator in &self.spectators {
+                net.ping(spectator, self.pong_state.ticks)?;
+            }
 
             self.last_ping = Instant::now();
         }
         Ok(())
     }
 
-    fn maybe_send_pong_state(&mut self, net: &mut ServerNet) -> Result<(), ErrorKind> {
+    fn maybe_send_pong_state(&mut self, net: &mut ServerNet) -> Result<()> {
         // Is it time for state broadcast?
         let duration_since_broadcast = Instant::now() - self.last_state_broadcast;
         if duration_since_broadcast >= Duration::from_millis(MS_PER_STATE_BROADCAST) {
-            net.send(self.p1, ServerMsg::State(self.pong_state))?;
-            net.send(self.p2, ServerMsg::State(self.pong_state))?;
+            self.send_state(net)?;
 
             self.last_state_broadcast = Instant::now();
         }
+
+        let duration_since_broadcast = Instant::now() - self.last_spectator_broadcast;
+        if duration_since_broadcast >= Duration::from_millis(MS_PER_SPECTATOR_BROADCAST) {
+            self.send_spectator_state(net)?;
+
+            self.last_spectator_broadcast = Instant::now();
+        }
+        Ok(())
+    }
+
+    // Spectators don't acknowledge anything, they always get the full state.
+    fn send_spectator_state(&mut self, net: &mut ServerNet) -> Result<()> {
+        for &spectator in &self.spectators {
+            net.send(spectator, ServerMsg::State(self.spectator_state(), InputAck::default()))?;
+        }
+        Ok(())
+    }
+
+    // Anyone can watch, so spectators don't get to see the rng. They only
+    // draw what they get, and at worst extrapolate a serve the wrong way until
+    // the next state arrives.
+    fn spectator_state(&self) -> PongState {
+        PongState { rng: Rng::new(0), ..self.pong_state }
+    }
+
+    // Sends the result, with the final score, to the players and spectators.
+    // It goes out reliably, unlike states, so no final state is sent: it might
+    // arrive after the result, when clients no longer care about states.
+    fn send_match_result(
+        &mut self,
+        net: &mut ServerNet,
+        winner: PlayerOrder
+    ) -> Result<()> {
+        let result = MatchResult {
+            winner,
+            p1_score: self.pong_state.p1_score,
+            p2_score: self.pong_state.p2_score,
+        };
+
+        net.send(self.p1, ServerMsg::MatchOver(result))?;
+        net.send(self.p2, ServerMsg::MatchOver(result))?;
+        for &spectator in &self.spectators {
+            net.send(spectator, ServerMsg::MatchOver(result))?;
+        }
         Ok(())
     }
 }
 
 impl ServerState for Running {
+    fn wants(&self, addr: SocketAddr, msg: &ClientMsg) -> bool {
+        match msg {
+            ClientMsg::Resume(_, token) if self.is_player_token(*token) => true,
+            _ => self.player_at(addr).is_some() || self.spectators.contains(&addr),
+        }
+    }
+
+    fn on_msg(&mut self, net: &mut ServerNet, addr: SocketAddr, msg: ClientMsg) -> StateUpdate {
+        match msg {
+            ClientMsg::Timeout | ClientMsg::Disconnect | ClientMsg::Resume(..) => {}
+            _ => self.heard_from(addr),
+        }
+
+        match msg {
+            ClientMsg::Move(input) => self.apply_move(addr, input),
+            ClientMsg::AckState(tick) => self.ack_state(addr, tick),
+            ClientMsg::Resume(_, token) => {
+                self.resume(net, addr, token, None)?;
+            }
+
+            ClientMsg::Spectate(_) => self.spectate(net, addr)?,
+
+            ClientMsg::Timeout | ClientMsg::Disconnect => {
+                // Old addresses of players that have resumed from somewhere
+                // else may still time out, they don't matter anymore.
+                if let Some(player) = self.player_at(addr) {
+                    return new_state(Box::new(Paused::new(self.clone(), player)));
+                }
+                self.forget_spectator(addr);
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
     fn update(&mut self, net: &mut ServerNet) -> StateUpdate {
         // Tell the clients its time to start. Technically not necessary since
         // clients will show whatever pong state they have.
-        net.send(self.p1, ServerMsg::Start(PlayerOrder::P1))?;
-        net.send(self.p2, ServerMsg::Start(PlayerOrder::P2))?;
-
-        loop {
-            while let Some((addr, event)) = net.poll() {
-                match event {
-                    ClientMsg::MoveUp => {
-                        if addr == self.p1 {
-                            self.p1_move = Some(PlayerMovement::Up);
-                        } else {
-                            self.p2_move = Some(PlayerMovement::Up);
-                        }
-                    }
-
-                    ClientMsg::MoveDown => {
-                        if addr == self.p1 {
-                            self.p1_move = Some(PlayerMovement::Down);
-                        } else {
-                            self.p2_move = Some(PlayerMovement::Down);
-                        }
-                    }
-
-                    ClientMsg::Timeout | ClientMsg::Disconnect => {
-                        // Any timeout will kill the game and the server will be
-                        // in its initial state again.
-
-                        net.send(self.p1, ServerMsg::Abort)?;
-                        net.send(self.p2, ServerMsg::Abort)?;
-                        return new_state(Box::new(WaitingForP1::new()));
-                    }
-                    _ => {}
-                }
-            }
+        if !self.started {
+            net.send(self.p1, ServerMsg::Start(PlayerOrder::P1, self.p1_token))?;
+            net.send(self.p2, ServerMsg::Start(PlayerOrder::P2, self.p2_token))?;
+            self.started = true;
+        }
+
+        self.tick();
+
+        if let Some(winner) = self.pong_state.winner() {
+            self.send_match_result(net, winner)?;
+            return new_state(Box::new(Ended::new()));
+        }
+
+        self.maybe_ping_clients(net)?;
+        self.maybe_send_pong_state(net)?;
+        Ok(None)
+    }
+
+    // Players can't watch their own match.
+    fn add_spectator(&mut self, net: &mut ServerNet, addr: SocketAddr) -> Result<bool> {
+        if self.player_at(addr).is_some() {
+            return Ok(false);
+        }
+
+        if !self.spectators.contains(&addr) {
+            self.spectators.push(addr);
+        }
+
+        let names = (self.player_name(net, PlayerOrder::P1), self.player_name(net, PlayerOrder::P2));
+        net.send(addr, ServerMsg::Watching(names.0, names.1))?;
+        net.send(addr, ServerMsg::State(self.spectator_state(), InputAck::default()))?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod running_tests {
+    use crate::net_messages::Hello;
+    use crate::test_helper::*;
+    use crate::transport::{MemoryNetwork, Transport};
+    use super::*;
+
+    fn running() -> Running {
+        let p1: SocketAddr = "127.0.0.1:45456".parse().unwrap();
+        let p2: SocketAddr = "127.0.0.1:45457".parse().unwrap();
+        Running::new(p1, p2)
+    }
+
+    fn input(seq: u32, movement: PlayerMovement, tick: u32) -> MoveInput {
+        MoveInput { seq, movement, tick }
+    }
+
+    #[test]
+    fn test_movement_is_kept_until_changed() {
+        let mut state = running();
+
+        state.apply_move(state.p1, input(1, PlayerMovement::Down, 0));
+        state.tick();
+        assert_eq!(state.p1_move, Some(PlayerMovement::Down));
+        assert_eq!(state.p2_move, None);
+
+        state.tick();
+        assert_eq!(state.p1_move, Some(PlayerMovement::Down));
+
+        state.apply_move(state.p1, input(2, PlayerMovement::Still, 0));
+        state.tick();
+        assert_eq!(state.p1_move, Some(PlayerMovement::Still));
+    }
+
+    #[test]
+    fn test_old_or_duplicated_movement_is_ignored() {
+        let mut state = running();
+
+        state.apply_move(state.p2, input(5, PlayerMovement::Up, 0));
+        state.apply_move(state.p2, input(4, PlayerMovement::Down, 0));
+        state.apply_move(state.p2, input(5, PlayerMovement::Down, 0));
+        state.tick();
+        state.apply_move(state.p2, input(5, PlayerMovement::Down, 0));
+        state.tick();
+
+        assert_eq!(state.p2_move, Some(PlayerMovement::Up));
+    }
+
+    #[test]
+    fn test_movement_waits_for_its_tick() {
+        let mut state = running();
+
+        state.apply_move(state.p1, input(1, PlayerMovement::Up, 3));
+        state.apply_move(state.p1, input(2, PlayerMovement::Down, 5));
+        for _ in 0..3 {
+            state.tick();
+            assert_eq!(state.p1_move, None);
+        }
+
+        state.tick();
+        assert_eq!(state.p1_move, Some(PlayerMovement::Up));
+        state.tick();
+        state.tick();
+        assert_eq!(state.p1_move, Some(PlayerMovement::Down));
+        assert_eq!(state.p1_ack, InputAck { seq: 2, ticks: 1 });
+    }
+
+    #[test]
+    fn test_movement_far_ahead_is_clamped() {
+        let mut state = running();
+
+        state.apply_move(state.p2, input(1, PlayerMovement::Up, 10_000));
+        for _ in 0..MAX_INPUT_LEAD_TICKS {
+            state.tick();
+        }
+        assert_eq!(state.p2_move, None);
 
-            self.pong_state.tick(self.p1_move, self.p2_move);
-            self.p1_move = None;
-            self.p2_move = None;
+        state.tick();
+        assert_eq!(state.p2_move, Some(PlayerMovement::Up));
+    }
 
-            self.maybe_ping_clients(net)?;
-            self.maybe_send_pong_state(net)?;
-            self.sleep();
+    #[test]
+    fn test_late_movement_is_applied_right_away() {
+        let mut state = running();
+        for _ in 0..10 {
+            state.tick();
         }
+
+        state.apply_move(state.p1, input(1, PlayerMovement::Down, 4));
+        state.tick();
+        assert_eq!(state.p1_move, Some(PlayerMovement::Down));
+    }
+
+    #[test]
+    fn test_input_ack_counts_ticks_since_latest_change() {
+        let mut state = running();
+
+        state.apply_move(state.p1, input(1, PlayerMovement::Down, 0));
+        state.tick();
+        state.tick();
+        assert_eq!(state.p1_ack, InputAck { seq: 1, ticks: 2 });
+
+        state.apply_move(state.p1, input(2, PlayerMovement::Up, 0));
+        state.tick();
+        assert_eq!(state.p1_ack, InputAck { seq: 2, ticks: 1 });
     }
 
-    fn sleep_time_ms(&self) -> u64 {
-        MS_PER_UPDATE
+    #[test]
+    fn test_movement_from_unknown_address_is_ignored() {
+        let mut state = running();
+        let other: SocketAddr = "127.0.0.1:45458".parse().unwrap();
+
+        state.apply_move(other, input(1, PlayerMovement::Up, 0));
+        state.tick();
+
+        assert_eq!(state.p1_move, None);
+        assert_eq!(state.p2_move, None);
+    }
+
+    #[test]
+    fn test_resume_moves_player_to_new_address() {
+        let network = MemoryNetwork::new();
+        let mut net = memory_server_net(&network);
+        let mut state = running();
+        let mut moved = network.bind(addr("127.0.0.1:45458"));
+        let silence = Duration::from_millis(RESUME_AFTER_SILENCE_MS);
+        state.p1_heard = Instant::now().checked_sub(silence).unwrap();
+
+        let resume = ClientMsg::Resume(Hello::new(), state.p1_token);
+        assert!(state.wants(moved.local_addr(), &resume));
+        assert!(state.on_msg(&mut net, moved.local_addr(), resume).unwrap().is_none());
+
+        assert_eq!(state.p1, moved.local_addr());
+        assert_eq!(
+            received_server_msgs(&mut moved),
+            vec![ServerMsg::Start(PlayerOrder::P1, state.p1_token)]
+        );
+    }
+
+    #[test]
+    fn test_resume_with_unknown_token_is_aborted() {
+        let network = MemoryNetwork::new();
+        let mut net = memory_server_net(&network);
+        let mut state = running();
+        let mut stranger = network.bind(addr("127.0.0.1:45458"));
+        let token = SessionToken(state.p1_token.0 ^ state.p2_token.0);
+
+        let resume = ClientMsg::Resume(Hello::new(), token);
+        assert!(!state.wants(stranger.local_addr(), &resume));
+        state.on_msg(&mut net, stranger.local_addr(), resume).unwrap();
+
+        assert_eq!(state.p1, addr("127.0.0.1:45456"));
+        assert_eq!(received_server_msgs(&mut stranger), vec![ServerMsg::Abort]);
+    }
+
+    #[test]
+    fn test_players_still_playing_cannot_be_pushed_out() {
+        let network = MemoryNetwork::new();
+        let mut net = memory_server_net(&network);
+        let mut state = running();
+        let mut intruder = network.bind(addr("127.0.0.1:45458"));
+
+        let resume = ClientMsg::Resume(Hello::new(), state.p2_token);
+        state.on_msg(&mut net, intruder.local_addr(), resume).unwrap();
+
+        assert_eq!(state.p2, addr("127.0.0.1:45457"));
+        assert_eq!(received_server_msgs(&mut intruder), vec![ServerMsg::Abort]);
+    }
+
+    #[test]
+    fn test_session_tokens_differ_between_matches() {
+        let first = running();
+        let second = running();
+
+        assert_ne!(first.p1_token, first.p2_token);
+        assert_ne!(first.p1_token, second.p1_token);
+    }
+
+    #[test]
+    fn test_match_is_paused_when_a_player_times_out() {
+        let network = MemoryNetwork::new();
+        let mut net = memory_server_net(&network);
+        let mut state = running();
+
+        // Timeouts from addresses that aren't playing don't matter.
+        let update = state.on_msg(&mut net, addr("127.0.0.1:45458"), ClientMsg::Timeout);
+        assert!(update.unwrap().is_none());
+
+        let update = state.on_msg(&mut net, state.p2, ClientMsg::Disconnect);
+        assert_state_update(update, "Paused");
+    }
+
+    #[test]
+    fn test_match_result_carries_the_final_score() {
+        let network = MemoryNetwork::new();
+        let mut net = memory_server_net(&network);
+        let mut state = running();
+        let mut p1 = network.bind(state.p1);
+        state.pong_state.p1_score = 11;
+        state.pong_state.p2_score = 4;
+
+        state.send_match_result(&mut net, PlayerOrder::P1).unwrap();
+
+        let result = MatchResult { winner: PlayerOrder::P1, p1_score: 11, p2_score: 4 };
+        assert_eq!(received_server_msgs(&mut p1), vec![ServerMsg::MatchOver(result)]);
+    }
+
+    #[test]
+    fn test_spectators_watch_but_cannot_play() {
+        let network = MemoryNetwork::new();
+        let mut net = memory_server_net(&network);
+        let mut state = running();
+        let mut spectator = network.bind(addr("127.0.0.1:45458"));
+        let watcher = spectator.local_addr();
+
+        assert!(state.add_spectator(&mut net, watcher).unwrap());
+        assert!(!state.add_spectator(&mut net, state.p1).unwrap());
+
+        let mut p1 = network.bind(state.p1);
+        state.on_msg(&mut net, state.p1, ClientMsg::Spectate(Hello::new())).unwrap();
+        assert_eq!(received_server_msgs(&mut p1), vec![ServerMsg::Abort]);
+
+        let msgs = received_server_msgs(&mut spectator);
+        let names = ServerMsg::Watching("Player 1".to_string(), "Player 2".to_string());
+        assert_eq!(msgs[0], names);
+        match &msgs[1] {
+            ServerMsg::State(state, _) => assert_eq!(state.rng, Rng::new(0)),
+            msg => panic!("Expected a state, got {:?}", msg),
+        }
+
+        let movement = ClientMsg::Move(input(1, PlayerMovement::Up, 0));
+        assert!(state.wants(watcher, &movement));
+        state.on_msg(&mut net, watcher, movement).unwrap();
+        state.tick();
+        assert_eq!(state.p1_move, None);
+        assert_eq!(state.p2_move, None);
+
+        // Spectators leaving doesn't pause the match.
+        assert!(state.on_msg(&mut net, watcher, ClientMsg::Timeout).unwrap().is_none());
+        assert!(!state.wants(watcher, &ClientMsg::Timeout));
     }
 }
@@ -1,21 +1,62 @@
-use bincode::{serialize};
-use crate::net_messages::{ClientMsg};
-use laminar::{Packet, Socket};
-use std::time::{Instant};
+use crate::channels::Channel;
+use crate::codec::{encode_client_msg, decode_server_msg};
+use crate::error::{Error, Result};
+use crate::net_messages::{ClientMsg, ServerMsg};
+use crate::transport::{Transport, TransportEvent, MemoryNetwork, MemoryTransport};
 use std::net::SocketAddr;
 use crate::server_state::{StateUpdate};
 use crate::server_network::ServerNet;
 
-pub fn send_client_msg(src: Option<SocketAddr>, dst: SocketAddr, msg: ClientMsg) {
-    let mut socket = match src {
-        Some(addr) => Socket::bind(addr),
-        None => Socket::bind_any(),
-    }.unwrap();
+pub fn addr(addr: &str) -> SocketAddr {
+    addr.parse().unwrap()
+}
+
+// A server listening on 127.0.0.1:6666 of an in-memory network.
+pub fn memory_server_net(network: &MemoryNetwork) -> ServerNet {
+    ServerNet::new(Box::new(network.bind(addr("127.0.0.1:6666"))))
+}
+
+// Like memory_server_net, but every send to `unreachable` fails.
+pub fn unreachable_server_net(network: &MemoryNetwork, unreachable: SocketAddr) -> ServerNet {
+    let transport = network.bind(addr("127.0.0.1:6666"));
+    ServerNet::new(Box::new(Unreachable { transport, unreachable }))
+}
 
-    let msg = serialize(&msg).unwrap();
-    let packet = Packet::unreliable(dst, msg);
-    socket.send(packet).unwrap();
-    socket.manual_poll(Instant::now());
+struct Unreachable {
+    transport: MemoryTransport,
+    unreachable: SocketAddr,
+}
+
+impl Transport for Unreachable {
+    fn local_addr(&self) -> SocketAddr {
+        self.transport.local_addr()
+    }
+
+    fn send(&mut self, dst: SocketAddr, payload: Vec<u8>, channel: Channel) -> Result<()> {
+        if dst == self.unreachable {
+            return Err(Error::Send(format!("{} is unreachable", dst)));
+        }
+        self.transport.send(dst, payload, channel)
+    }
+
+    fn recv(&mut self) -> Option<TransportEvent> {
+        self.transport.recv()
+    }
+}
+
+pub fn send_client_msg(client: &mut MemoryTransport, dst: SocketAddr, msg: ClientMsg) {
+    client.send(dst, encode_client_msg(&msg), Channel::for_client_msg(&msg)).unwrap();
+}
+
+// Everything the client has got from the server so far.
+pub fn received_server_msgs(client: &mut MemoryTransport) -> Vec<ServerMsg> {
+    let mut msgs = vec![];
+    while let Some(event) = client.recv() {
+        if let TransportEvent::Packet(_, payload, _) = event {
+            msgs.push(decode_server_msg(&payload).unwrap());
+        }
+    }
+    msgs
 }
 
 pub fn assert_state_update(state: StateUpdate, new_state: &str) {
@@ -23,6 +64,7 @@ pub fn assert_state_update(state: StateUpdate, new_state: &str) {
     assert!(name.contains(new_state));
 }
 
+// For the few tests that need real sockets.
 // This might not be the best thing
 // from https://github.com/rust-lang-nursery/rust-cookbook/issues/500
 pub fn working_server_net() -> ServerNet {
@@ -0,0 +1,302 @@
+// How the server and the clients get packets to each other. Everything above
+// this deals in whole messages and doesn't care whether they travel over a
+// real socket or stay within the process, like in tests.
+
+use crate::channels::Channel;
+use crate::error::{Error, Result};
+#[cfg(feature = "client")]
+use crossbeam_channel::{Sender, Receiver};
+use laminar::{OrderingGuarantee, Packet, Socket, SocketEvent};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransportEvent {
+    // From an address, on the channel it was sent on.
+    Packet(SocketAddr, Vec<u8>, Channel),
+    Connect(SocketAddr),
+    Timeout(SocketAddr),
+    Disconnect(SocketAddr),
+}
+
+pub trait Transport {
+    fn local_addr(&self) -> SocketAddr;
+
+    fn send(&mut self, dst: SocketAddr, payload: Vec<u8>, channel: Channel) -> Result<()>;
+
+    // Never blocks, None when there is nothing more to receive right now.
+    fn recv(&mut self) -> Option<TransportEvent>;
+}
+
+// UDP through laminar. Packets are only sent and received while the
+// transport is used, there is no polling thread.
+pub struct LaminarTransport {
+    socket: Socket,
+    addr: SocketAddr,
+}
+
+impl LaminarTransport {
+    pub fn bind(addr: SocketAddr) -> Result<Self> {
+        let label = addr.to_string();
+        let socket = Socket::bind(addr).map_err(|err| bind_error(&label, err))?;
+        Self::new(socket, &label)
+    }
+
+    // Any free local port.
+    pub fn bind_any() -> Result<Self> {
+        Self::new(Socket::bind_any().map_err(|err| bind_error("any port", err))?, "any port")
+    }
+
+    // `label` is what we tried to bind to, for the error.
+    fn new(socket: Socket, label: &str) -> Result<Self> {
+        let addr = socket.local_addr().map_err(|err| bind_error(label, err))?;
+        Ok(Self { socket, addr })
+    }
+}
+
+impl Transport for LaminarTransport {
+    fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn send(&mut self, dst: SocketAddr, payload: Vec<u8>, channel: Channel) -> Result<()> {
+        self.socket
+            .send(packet(channel, dst, payload))
+            .map_err(|err| Error::Send(err.to_string()))?;
+        self.socket.manual_poll(Instant::now());
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Option<TransportEvent> {
+        // Only go to the socket once everything already received is handled.
+        let event = match self.socket.recv() {
+            Some(event) => event,
+            None => {
+                self.socket.manual_poll(Instant::now());
+                self.socket.recv()?
+            }
+        };
+
+        Some(TransportEvent::from(event))
+    }
+}
+
+fn bind_error(addr: &str, err: laminar::ErrorKind) -> Error {
+    Error::Bind(addr.to_string(), err.to_string())
+}
+
+fn stream_id(channel: Channel) -> u8 {
+    match channel {
+        Channel::Control => 0,
+        Channel::State => 1,
+        Channel::Input => 2,
+        Channel::Clock => 3,
+    }
+}
+
+// Control is reliable and ordered, every other channel only delivers packets
+// newer than the last one that arrived.
+fn packet(channel: Channel, dst: SocketAddr, payload: Vec<u8>) -> Packet {
+    let stream_id = Some(stream_id(channel));
+    match channel {
+        Channel::Control => Packet::reliable_ordered(dst, payload, stream_id),
+        _ => Packet::unreliable_sequenced(dst, payload, stream_id),
+    }
+}
+
+// The channel a packet we got was sent on. Anything we wouldn't have sent
+// ourselves counts as control.
+fn channel_of(packet: &Packet) -> Channel {
+    match packet.order_guarantee() {
+        OrderingGuarantee::Sequenced(Some(1)) => Channel::State,
+        OrderingGuarantee::Sequenced(Some(2)) => Channel::Input,
+        OrderingGuarantee::Sequenced(Some(3)) => Channel::Clock,
+        _ => Channel::Control,
+    }
+}
+
+impl From<SocketEvent> for TransportEvent {
+    fn from(event: SocketEvent) -> Self {
+        match event {
+            SocketEvent::Packet(pkt) => {
+                let channel = channel_of(&pkt);
+                TransportEvent::Packet(pkt.addr(), pkt.payload().to_vec(), channel)
+            }
+            SocketEvent::Connect(addr) => TransportEvent::Connect(addr),
+            SocketEvent::Timeout(addr) => TransportEvent::Timeout(addr),
+            SocketEvent::Disconnect(addr) => TransportEvent::Disconnect(addr),
+        }
+    }
+}
+
+// UDP through laminar, with the socket polled in a thread of its own so that
+// networking doesn't mess up the update/drawing rate of the client.
+#[cfg(feature = "client")]
+pub struct ThreadedLaminarTransport {
+    addr: SocketAddr,
+
+    // channel ends for sending/receiving data to/from the socket
+    sender: Sender<Packet>,
+    receiver: Receiver<SocketEvent>,
+}
+
+#[cfg(feature = "client")]
+impl ThreadedLaminarTransport {
+    // Any free local port.
+    pub fn bind_any() -> Result<Self> {
+        let mut socket = Socket::bind_any().map_err(|err| bind_error("any port", err))?;
+        let addr = socket.local_addr().map_err(|err| bind_error("any port", err))?;
+        let sender = socket.get_packet_sender();
+        let receiver = socket.get_event_receiver();
+
+        std::thread::spawn(move || socket.start_polling());
+        Ok(Self { addr, sender, receiver })
+    }
+}
+
+#[cfg(feature = "client")]
+impl Transport for ThreadedLaminarTransport {
+    fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn send(&mut self, dst: SocketAddr, payload: Vec<u8>, channel: Channel) -> Result<()> {
+        self.sender
+            .send(packet(channel, dst, payload))
+            .map_err(|_| Error::Send(String::from("the socket thread is gone")))
+    }
+
+    fn recv(&mut self) -> Option<TransportEvent> {
+        self.receiver.try_recv().ok().map(TransportEvent::from)
+    }
+}
+
+// A network within the process. Everything sent arrives, in order, at once.
+// Packets to addresses nobody has bound are lost, just like with UDP.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    inboxes: Arc<Mutex<HashMap<SocketAddr, VecDeque<TransportEvent>>>>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bind(&self, addr: SocketAddr) -> MemoryTransport {
+        self.inboxes.lock().unwrap().entry(addr).or_default();
+        MemoryTransport { addr, network: self.clone() }
+    }
+
+    // Hands dst an event as if it came from the network, like a timeout.
+    pub fn deliver(&self, dst: SocketAddr, event: TransportEvent) {
+        if let Some(inbox) = self.inboxes.lock().unwrap().get_mut(&dst) {
+            inbox.push_back(event);
+        }
+    }
+}
+
+pub struct MemoryTransport {
+    addr: SocketAddr,
+    network: MemoryNetwork,
+}
+
+impl Transport for MemoryTransport {
+    fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn send(&mut self, dst: SocketAddr, payload: Vec<u8>, channel: Channel) -> Result<()> {
+        self.network.deliver(dst, TransportEvent::Packet(self.addr, payload, channel));
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Option<TransportEvent> {
+        self.network.inboxes.lock().unwrap().get_mut(&self.addr)?.pop_front()
+    }
+}
+
+// Frees the address for someone else.
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        self.network.inboxes.lock().unwrap().remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod transport_tests {
+    use laminar::DeliveryGuarantee;
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn test_memory_transport_delivers_in_order() {
+        let network = MemoryNetwork::new();
+        let mut a = network.bind(addr(1));
+        let mut b = network.bind(addr(2));
+
+        a.send(addr(2), vec![1], Channel::State).unwrap();
+        a.send(addr(2), vec![2], Channel::Control).unwrap();
+
+        assert_eq!(b.recv(), Some(TransportEvent::Packet(addr(1), vec![1], Channel::State)));
+        assert_eq!(b.recv(), Some(TransportEvent::Packet(addr(1), vec![2], Channel::Control)));
+        assert_eq!(b.recv(), None);
+        assert_eq!(a.recv(), None);
+    }
+
+    #[test]
+    fn test_memory_transport_loses_packets_to_nobody() {
+        let network = MemoryNetwork::new();
+        let mut a = network.bind(addr(1));
+
+        {
+            let _b = network.bind(addr(2));
+        }
+        a.send(addr(2), vec![1], Channel::State).unwrap();
+
+        let mut b = network.bind(addr(2));
+        assert_eq!(b.recv(), None);
+    }
+
+    #[test]
+    fn test_states_are_sequenced_and_control_is_reliable() {
+        let state = packet(Channel::State, addr(1), vec![]);
+        assert_eq!(state.delivery_guarantee(), DeliveryGuarantee::Unreliable);
+        assert_eq!(state.order_guarantee(), OrderingGuarantee::Sequenced(Some(1)));
+
+        let control = packet(Channel::Control, addr(1), vec![]);
+        assert_eq!(control.delivery_guarantee(), DeliveryGuarantee::Reliable);
+        assert_eq!(control.order_guarantee(), OrderingGuarantee::Ordered(Some(0)));
+    }
+
+    #[test]
+    fn test_packets_tell_which_channel_they_were_sent_on() {
+        for &channel in &[Channel::Control, Channel::State, Channel::Input, Channel::Clock] {
+            assert_eq!(channel_of(&packet(channel, addr(1), vec![])), channel);
+        }
+    }
+
+    #[test]
+    fn test_laminar_transport_sends_over_udp() {
+        let mut a = LaminarTransport::bind_any().unwrap();
+        let mut b = LaminarTransport::bind_any().unwrap();
+
+        a.send(b.local_addr(), vec![1, 2, 3], Channel::Control).unwrap();
+
+        for _ in 0..100 {
+            if let Some(TransportEvent::Packet(src, payload, channel)) = b.recv() {
+                assert_eq!(src, a.local_addr());
+                assert_eq!(payload, vec![1, 2, 3]);
+                assert_eq!(channel, Channel::Control);
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        panic!("Nothing arrived");
+    }
+}